@@ -0,0 +1,125 @@
+use anyhow::{Context as _, anyhow, bail};
+use clap::Subcommand;
+use futures_util::future::join_all;
+use mongodb::bson::Timestamp;
+use serde_json::json;
+
+use crate::db::{self, ReplayStart};
+use crate::{Args, http_api, replay, tls};
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Validate the configuration and exit
+    CheckConfig,
+
+    /// Print the initial data the subscribe proxy would return for a document
+    Snapshot {
+        /// Document identifier
+        id: String,
+    },
+
+    /// Republish past changes, until the end time or the time the replay started
+    Replay {
+        /// RFC 3339 time or resume token to replay changes from
+        #[arg(long)]
+        from: ReplayStart,
+
+        /// RFC 3339 time to stop replaying changes at
+        #[arg(long, value_parser = replay::parse_timestamp)]
+        to: Option<Timestamp>,
+    },
+
+    /// Publish a test message on a dedicated channel of the namespace, to all Centrifugo clusters
+    PublishTest,
+}
+
+/// Identifier completing the namespace into the channel of test publications, which is not the
+/// channel of any document unless one has this identifier.
+const TEST_CHANNEL_ID: &str = "centrifugo-change-stream-publish-test";
+
+impl Command {
+    pub(crate) async fn run(&self, args: &Args) -> anyhow::Result<()> {
+        match self {
+            Self::CheckConfig => check_config(args).await,
+            Self::Snapshot { id } => snapshot(args, id).await,
+            Self::Replay { from, to } => replay(args, from, *to).await,
+            Self::PublishTest => publish_test(args).await,
+        }
+    }
+}
+
+async fn check_config(args: &Args) -> anyhow::Result<()> {
+    args.settings()?;
+    tls::acceptor(&args.common, &args.tls)?;
+    http_api::ProxyAuth::new(&args.http_api)?;
+    args.mongodb.lookup_cache()?;
+    let collection = db::create_collection(&args.mongodb).await?;
+    http_api::Admin::new(
+        &args.http_api,
+        collection,
+        &args.mongodb,
+        args.publication_mode,
+    )?;
+    println!("configuration is valid");
+    Ok(())
+}
+
+async fn snapshot(args: &Args, id: &str) -> anyhow::Result<()> {
+    let settings = args.settings()?;
+    let collection = db::create_collection(&args.mongodb).await?;
+    let data = collection.current_data(&args.mongodb, id).await?;
+    let channel = format!("{}:{id}", collection.namespace());
+    let data = http_api::initial_data(settings.transform.as_deref(), &channel, data, None)
+        .map_err(|err| anyhow!(err))
+        .context("error in transform script")?;
+    println!("{}", serde_json::to_string_pretty(&data)?);
+    Ok(())
+}
+
+async fn replay(args: &Args, from: &ReplayStart, to: Option<Timestamp>) -> anyhow::Result<()> {
+    let settings = args.settings()?;
+    let collection = db::create_collection(&args.mongodb).await?;
+    let outcome = replay::replay(
+        &collection,
+        &args.mongodb,
+        args.publication_mode,
+        &settings,
+        from,
+        to,
+    )
+    .await?;
+
+    if let Some(resume_token) = outcome.resume_token {
+        println!("resume token: {}", serde_json::to_string(&resume_token)?);
+    }
+    if outcome.failures > 0 {
+        bail!("{} publication(s) failed", outcome.failures);
+    }
+    Ok(())
+}
+
+async fn publish_test(args: &Args) -> anyhow::Result<()> {
+    let settings = args.settings()?;
+    let collection = db::create_collection(&args.mongodb).await?;
+    // Not a document channel, whose subscribers expect publications of the configured mode.
+    let channel = format!("{}:{TEST_CHANNEL_ID}", collection.namespace());
+    let data = json!({ "test": true });
+
+    let outcomes =
+        join_all(settings.centrifugo_clients.iter().map(|client| async {
+            (client.name(), client.publish(&channel, &data).await.is_ok())
+        }))
+        .await;
+
+    let mut failed = Vec::new();
+    for (name, success) in outcomes {
+        println!("{name}: {}", if success { "ok" } else { "failed" });
+        if !success {
+            failed.push(name);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("publishing failed on clusters: {}", failed.join(", "));
+    }
+    Ok(())
+}
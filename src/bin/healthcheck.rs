@@ -1,29 +1,274 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::{Context as _, anyhow};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use reqwest::{Certificate, Client};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+use url::Url;
+
+use centrifugo_change_stream::{CommonArgs, install_crypto_provider};
 
-use centrifugo_change_stream::CommonArgs;
+#[derive(Clone, Copy, ValueEnum)]
+enum Probe {
+    /// Check that the service is alive
+    Liveness,
+    /// Check that the service is ready, i.e. all health checks pass
+    Readiness,
+}
+
+impl Probe {
+    fn endpoint(&self) -> &'static str {
+        match self {
+            Self::Liveness => "livez",
+            Self::Readiness => "readyz",
+        }
+    }
+}
 
 #[derive(Parser)]
 struct Args {
     #[command(flatten)]
     common: CommonArgs,
+
+    /// Base URL of the service (defaults to the local listen address, with HTTPS if TLS is
+    /// enabled)
+    #[arg(env, long)]
+    healthcheck_url: Option<Url>,
+
+    /// PEM file of certificate authorities for verifying the service certificate (otherwise, only
+    /// the certificate of `--listen-tls-certificate-file` is accepted)
+    #[arg(env, long)]
+    healthcheck_tls_ca_file: Option<PathBuf>,
+
+    /// Timeout of requests (e.g. `5s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "5s")]
+    healthcheck_timeout: Duration,
+
+    /// Endpoint to check
+    #[arg(env, long, value_enum, default_value_t = Probe::Readiness)]
+    healthcheck_probe: Probe,
+
+    /// Print the outcome and the detailed health report
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+impl Args {
+    fn base_url(&self) -> Url {
+        if let Some(url) = &self.healthcheck_url {
+            return url.clone();
+        }
+        let scheme = match self.common.listen_tls_certificate_file {
+            Some(_) => "https",
+            None => "http",
+        };
+        let url = format!("{scheme}://127.0.0.1:{}", self.common.listen_address.port());
+        url.parse().unwrap()
+    }
+}
+
+/// Accepts the server certificate only if it is the one of the certificate file, as the service
+/// is reached by IP address, which does not match certificate names.
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::General("unexpected server certificate".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn tls_client_config(certificate_path: &Path) -> anyhow::Result<ClientConfig> {
+    let certificate = CertificateDer::pem_file_iter(certificate_path)
+        .context("error reading certificate file")?
+        .next()
+        .context("no certificate in certificate file")?
+        .context("error reading certificate file")?;
+    let provider = Arc::new(ring::default_provider());
+    let verifier = PinnedCertificate {
+        certificate,
+        provider: Arc::clone(&provider),
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .context("error setting TLS protocol versions")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(config)
+}
+
+fn http_client(args: &Args) -> anyhow::Result<Client> {
+    let mut builder = Client::builder().timeout(args.healthcheck_timeout);
+    if let Some(path) = &args.healthcheck_tls_ca_file {
+        let pem = std::fs::read(path).context("error reading CA file")?;
+        for certificate in Certificate::from_pem_bundle(&pem).context("error parsing CA file")? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    } else if let Some(path) = &args.common.listen_tls_certificate_file {
+        builder = builder.tls_backend_preconfigured(tls_client_config(path)?);
+    }
+    builder.build().context("error building HTTP client")
+}
+
+/// Returns the URL of an endpoint, relative to the base URL.
+fn endpoint_url(base_url: &Url, endpoint: &str) -> anyhow::Result<Url> {
+    let mut url = base_url.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid base URL: {base_url}"))?
+        .pop_if_empty()
+        .push(endpoint);
+    Ok(url)
+}
+
+async fn print_report(client: &Client, base_url: &Url) -> anyhow::Result<()> {
+    let report = client
+        .get(endpoint_url(base_url, "health")?)
+        .send()
+        .await
+        .context("request error")?
+        .json::<serde_json::Value>()
+        .await
+        .context("error getting health report")?;
+    let report = serde_json::to_string_pretty(&report).context("error formatting report")?;
+    println!("{report}");
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let url = format!(
-        "http://127.0.0.1:{}/health",
-        args.common.listen_address.port()
-    );
+    install_crypto_provider();
+
+    let client = http_client(&args)?;
+    let base_url = args.base_url();
+    let endpoint = args.healthcheck_probe.endpoint();
 
-    let resp = reqwest::get(url).await.context("request error")?;
+    let resp = client
+        .get(endpoint_url(&base_url, endpoint)?)
+        .send()
+        .await
+        .context("request error")?;
+    let status = resp.status();
+    let body = resp.text().await.context("error getting response body")?;
 
-    if resp.status().is_success() {
+    if args.verbose {
+        println!("{endpoint}: {status}");
+        print_report(&client, &base_url).await?;
+    }
+
+    if status.is_success() {
         Ok(())
     } else {
-        let body = resp.text().await.context("error getting response body")?;
         Err(anyhow!(body))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse_from(std::iter::once(&"healthcheck").chain(args))
+    }
+
+    mod base_url {
+        use super::*;
+
+        #[test]
+        fn local() {
+            let args = args(&["--listen-address", "0.0.0.0:1234"]);
+            assert_eq!(args.base_url().as_str(), "http://127.0.0.1:1234/");
+        }
+
+        #[test]
+        fn local_tls() {
+            let args = args(&["--listen-tls-certificate-file", "/cert.pem"]);
+            assert_eq!(args.base_url().as_str(), "https://127.0.0.1:8080/");
+        }
+
+        #[test]
+        fn custom() {
+            let args = args(&["--healthcheck-url", "https://example.com/prefix"]);
+            assert_eq!(args.base_url().as_str(), "https://example.com/prefix");
+        }
+    }
+
+    mod endpoint_url {
+        use super::*;
+
+        #[test]
+        fn root() {
+            let base_url = "http://127.0.0.1:8080".parse().unwrap();
+            let url = endpoint_url(&base_url, "livez").unwrap();
+            assert_eq!(url.as_str(), "http://127.0.0.1:8080/livez");
+        }
+
+        #[test]
+        fn with_path() {
+            for base_url in ["https://example.com/prefix", "https://example.com/prefix/"] {
+                let url = endpoint_url(&base_url.parse().unwrap(), "readyz").unwrap();
+                assert_eq!(url.as_str(), "https://example.com/prefix/readyz");
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::model::{ChangeEvent, MongoDBData};
+
+struct Entry {
+    data: MongoDBData,
+    /// Tick of the last use, keying the entry in the recency order.
+    last_used: u64,
+}
+
+/// Lookup in progress for a document.
+#[derive(Default)]
+struct PendingLookup {
+    count: usize,
+    /// Whether a change of the document has been seen since the lookup started.
+    stale: bool,
+}
+
+struct Inner {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// Document identifiers by tick of last use, the least recently used first.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    pending: HashMap<String, PendingLookup>,
+}
+
+impl Inner {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+/// Current data of documents, warmed by lookups and kept up to date by change events, the
+/// least recently used documents being evicted when full.
+#[derive(Clone)]
+pub(crate) struct CurrentDataCache(Arc<Mutex<Inner>>);
+
+impl CurrentDataCache {
+    pub(crate) fn new(capacity: NonZeroUsize) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            capacity: capacity.get(),
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            pending: HashMap::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns the cached data of a document, if any.
+    pub(crate) fn get(&self, id: &str) -> Option<MongoDBData> {
+        let mut inner = self.lock();
+        let tick = inner.next_tick();
+        let entry = inner.entries.get_mut(id)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let data = entry.data.clone();
+        inner.recency.remove(&previous);
+        inner.recency.insert(tick, id.to_string());
+        Some(data)
+    }
+
+    /// Records the start of a lookup of a document.
+    pub(crate) fn begin_lookup(&self, id: &str) {
+        self.lock().pending.entry(id.to_string()).or_default().count += 1;
+    }
+
+    /// Records the outcome of a lookup of a document, caching the data unless the document has
+    /// changed meanwhile.
+    pub(crate) fn finish_lookup(&self, id: &str, data: Option<&MongoDBData>) {
+        let mut inner = self.lock();
+        let Some(pending) = inner.pending.get_mut(id) else {
+            return;
+        };
+        pending.count -= 1;
+        let stale = pending.stale;
+        if pending.count == 0 {
+            inner.pending.remove(id);
+        }
+        let Some(data) = data else {
+            return;
+        };
+        if stale || inner.entries.contains_key(id) {
+            return;
+        }
+        if inner.entries.len() >= inner.capacity
+            && let Some((_, evicted)) = inner.recency.pop_first()
+        {
+            inner.entries.remove(&evicted);
+        }
+        let tick = inner.next_tick();
+        inner.entries.insert(
+            id.to_string(),
+            Entry {
+                data: data.clone(),
+                last_used: tick,
+            },
+        );
+        inner.recency.insert(tick, id.to_string());
+    }
+
+    /// Applies a change event to the cached data of its document.
+    pub(crate) fn apply(&self, event: &ChangeEvent) {
+        let mut inner = self.lock();
+        let id = match event {
+            ChangeEvent::Update(event) => event.document_id(),
+            ChangeEvent::Replace { id, .. } | ChangeEvent::Delete { id } => id,
+            ChangeEvent::Invalidate => {
+                inner.entries.clear();
+                inner.recency.clear();
+                for pending in inner.pending.values_mut() {
+                    pending.stale = true;
+                }
+                return;
+            }
+        };
+        if let Some(pending) = inner.pending.get_mut(id) {
+            pending.stale = true;
+        }
+        let Some(entry) = inner.entries.get_mut(id) else {
+            return;
+        };
+        let applied = match event {
+            ChangeEvent::Update(event) => event.apply_to(&mut entry.data),
+            ChangeEvent::Replace {
+                data: Some(data), ..
+            } => {
+                entry.data = data.clone();
+                true
+            }
+            _ => false,
+        };
+        if !applied {
+            inner.remove(id);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::Bson;
+    use serde_json::json;
+
+    use super::*;
+
+    fn data(value: i32) -> MongoDBData {
+        let mut data = MongoDBData::with_capacity(1);
+        data.insert_value("first".to_string(), Bson::Int32(value));
+        data
+    }
+
+    fn cache(capacity: usize) -> CurrentDataCache {
+        CurrentDataCache::new(NonZeroUsize::new(capacity).unwrap())
+    }
+
+    fn warm(cache: &CurrentDataCache, id: &str, value: i32) {
+        cache.begin_lookup(id);
+        cache.finish_lookup(id, Some(&data(value)));
+    }
+
+    fn value(cache: &CurrentDataCache, id: &str) -> Option<String> {
+        cache
+            .get(id)
+            .map(|data| serde_json::to_value(data).unwrap()["val"]["first"].to_string())
+    }
+
+    fn update_event(id: &str, update_description: serde_json::Value) -> ChangeEvent {
+        ChangeEvent::Update(
+            serde_json::from_value(json!({
+                "ns": { "db": "db", "coll": "coll" },
+                "documentKey": { "_id": id },
+                "updateDescription": update_description,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn warmed_by_lookup() {
+        let cache = cache(2);
+        assert!(cache.get("doc").is_none());
+        warm(&cache, "doc", 1);
+        assert_eq!(value(&cache, "doc").as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn missing_document() {
+        let cache = cache(2);
+        cache.begin_lookup("doc");
+        cache.finish_lookup("doc", None);
+        assert!(cache.get("doc").is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn least_recently_used_eviction() {
+        let cache = cache(2);
+        warm(&cache, "first", 1);
+        warm(&cache, "second", 2);
+        cache.get("first");
+        warm(&cache, "third", 3);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("second").is_none());
+        assert!(cache.get("first").is_some());
+        assert!(cache.get("third").is_some());
+    }
+
+    #[test]
+    fn updated_by_change_event() {
+        let cache = cache(2);
+        warm(&cache, "doc", 1);
+        cache.apply(&update_event(
+            "doc",
+            json!({ "updatedFields": { "val.first": 5 } }),
+        ));
+        assert_eq!(value(&cache, "doc").as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn removed_field() {
+        let cache = cache(2);
+        warm(&cache, "doc", 1);
+        cache.apply(&update_event(
+            "doc",
+            json!({ "updatedFields": {}, "removedFields": ["val.first"] }),
+        ));
+        assert!(cache.get("doc").is_none());
+    }
+
+    #[test]
+    fn changed_before_lookup() {
+        let cache = cache(2);
+        cache.apply(&update_event(
+            "doc",
+            json!({ "updatedFields": { "val.first": 5 } }),
+        ));
+        warm(&cache, "doc", 5);
+        assert_eq!(value(&cache, "doc").as_deref(), Some("5"));
+        cache.apply(&update_event(
+            "doc",
+            json!({ "updatedFields": { "val.first": 6 } }),
+        ));
+        assert_eq!(value(&cache, "doc").as_deref(), Some("6"));
+    }
+
+    #[test]
+    fn changed_during_lookup() {
+        let cache = cache(2);
+        cache.begin_lookup("doc");
+        cache.apply(&update_event(
+            "doc",
+            json!({ "updatedFields": { "val.first": 5 } }),
+        ));
+        cache.finish_lookup("doc", Some(&data(1)));
+        assert!(cache.get("doc").is_none());
+
+        warm(&cache, "doc", 5);
+        assert_eq!(value(&cache, "doc").as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn replaced() {
+        let cache = cache(2);
+        warm(&cache, "doc", 1);
+        cache.apply(&ChangeEvent::Replace {
+            id: "doc".to_string(),
+            data: Some(data(7)),
+        });
+        assert_eq!(value(&cache, "doc").as_deref(), Some("7"));
+        cache.apply(&ChangeEvent::Replace {
+            id: "doc".to_string(),
+            data: None,
+        });
+        assert!(cache.get("doc").is_none());
+    }
+
+    #[test]
+    fn deleted() {
+        let cache = cache(2);
+        warm(&cache, "doc", 1);
+        warm(&cache, "other", 2);
+        cache.apply(&ChangeEvent::Delete {
+            id: "doc".to_string(),
+        });
+        assert!(cache.get("doc").is_none());
+        assert_eq!(value(&cache, "other").as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn deleted_during_lookup() {
+        let cache = cache(2);
+        cache.begin_lookup("doc");
+        cache.apply(&ChangeEvent::Delete {
+            id: "doc".to_string(),
+        });
+        cache.finish_lookup("doc", Some(&data(1)));
+        assert!(cache.get("doc").is_none());
+    }
+
+    #[test]
+    fn invalidated() {
+        let cache = cache(2);
+        warm(&cache, "doc", 1);
+        cache.begin_lookup("other");
+        cache.apply(&ChangeEvent::Invalidate);
+        cache.finish_lookup("other", Some(&data(2)));
+        assert_eq!(cache.len(), 0);
+    }
+}
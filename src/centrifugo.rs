@@ -1,141 +1,1026 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
+use anyhow::Context as _;
 use clap::Args;
-use reqwest::Client as HttpClient;
+use futures_util::future::join_all;
+use mongodb::change_stream::event::ResumeToken;
+use reqwest::{Certificate, Client as HttpClient, Identity};
+use serde::de::{DeserializeOwned, IgnoredAny};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{Value, json};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tracing::{Instrument, debug, error, info, info_span, instrument};
 use url::Url;
 
 use crate::channel::{RoundtripSender, roundtrip_channel};
-use crate::model::UpdateEvent;
+use crate::delivery::{Deliveries, Ticket};
+use crate::filter::Filter;
+use crate::model::{Publication, UpdateEvent};
+use crate::secret::Secret;
+use crate::settings::{Settings, SettingsReceiver};
+use crate::subscriptions::ActiveChannels;
+use crate::throttle::Throttle;
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 #[group(skip)]
 pub(crate) struct Config {
-    /// Centrifugo server base URL
-    #[arg(env, long, default_value = "http://centrifugo:8000")]
-    centrifugo_url: Url,
+    /// Centrifugo server base URL (comma-separated list of nodes, in order of preference, for
+    /// failover)
+    #[arg(
+        env,
+        long,
+        value_delimiter = ',',
+        default_value = "http://centrifugo:8000"
+    )]
+    centrifugo_url: Vec<Url>,
 
     /// Centrifugo API key
+    #[arg(env, long, hide_env_values = true)]
+    centrifugo_api_key: Option<String>,
+
+    /// File containing the Centrifugo API key (read again when modified)
+    #[arg(env, long, conflicts_with = "centrifugo_api_key")]
+    centrifugo_api_key_file: Option<PathBuf>,
+
+    /// Timeout for connecting to Centrifugo (e.g. `5s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "5s")]
+    centrifugo_connect_timeout: Duration,
+
+    /// Timeout for Centrifugo API requests, from connection to end of response (e.g. `10s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "10s")]
+    centrifugo_request_timeout: Duration,
+
+    /// PEM file of certificate authorities for validating Centrifugo server certificate, in
+    /// addition to system ones
+    #[arg(env, long)]
+    centrifugo_tls_ca_file: Option<PathBuf>,
+
+    /// PEM file containing the client certificate for Centrifugo TLS client authentication
+    #[arg(env, long)]
+    centrifugo_tls_certificate_file: Option<PathBuf>,
+
+    /// PEM file containing the private key for Centrifugo TLS client authentication
+    #[arg(env, long)]
+    centrifugo_tls_key_file: Option<PathBuf>,
+
+    /// Maximum number of idle connections to Centrifugo kept in the pool
     #[arg(env, long)]
-    centrifugo_api_key: String,
+    centrifugo_pool_max_idle: Option<usize>,
+
+    /// Duration after which idle connections to Centrifugo are closed (e.g. `90s`)
+    #[arg(env, long, value_parser = humantime::parse_duration)]
+    centrifugo_pool_idle_timeout: Option<Duration>,
+
+    /// Use HTTP/2 without negotiation with Centrifugo (HTTP/2 is otherwise negotiated with HTTPS)
+    #[arg(env, long)]
+    centrifugo_http2_prior_knowledge: bool,
+
+    /// Duration for which the outcome of Centrifugo health checks is reused (e.g. `5s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "5s")]
+    centrifugo_health_cache_ttl: Duration,
+
+    /// Number of consecutive errors after which a Centrifugo node is considered unhealthy
+    #[arg(env, long, default_value = "3")]
+    centrifugo_node_failure_threshold: NonZeroU32,
+
+    /// Interval after which an unhealthy Centrifugo node is tried again (e.g. `10s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "10s")]
+    centrifugo_node_probe_interval: Duration,
+
+    /// Additional Centrifugo cluster to publish to, as `name=NAME,url=URL,api_key=KEY` or
+    /// `name=NAME,url=URL,api_key_file=PATH`, `url` being repeatable for failover (may be
+    /// repeated)
+    #[arg(env, long, hide_env_values = true)]
+    centrifugo_cluster: Vec<ClusterSpec>,
+}
+
+impl Config {
+    pub(crate) fn health_cache_ttl(&self) -> Duration {
+        self.centrifugo_health_cache_ttl
+    }
+}
+
+/// Name of the cluster given by `--centrifugo-url` and `--centrifugo-api-key`.
+const DEFAULT_CLUSTER: &str = "default";
+
+#[derive(Clone, Debug)]
+struct ClusterSpec {
+    name: String,
+    urls: Vec<Url>,
+    api_key: Option<String>,
+    api_key_file: Option<PathBuf>,
+}
+
+impl FromStr for ClusterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut name, mut urls, mut api_key, mut api_key_file) = (None, Vec::new(), None, None);
+        for item in s.split(',') {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("missing `=` in `{item}`"))?;
+            match key.trim() {
+                "name" => name = Some(value.to_string()),
+                "url" => {
+                    let parsed = value
+                        .parse::<Url>()
+                        .map_err(|err| format!("invalid URL `{value}`: {err}"))?;
+                    urls.push(parsed);
+                }
+                "api_key" => api_key = Some(value.to_string()),
+                "api_key_file" => api_key_file = Some(PathBuf::from(value)),
+                other => return Err(format!("unknown key `{other}`")),
+            }
+        }
+        let name = name
+            .filter(|name| !name.is_empty())
+            .ok_or("missing cluster name")?;
+        if urls.is_empty() {
+            return Err("missing cluster URL".to_string());
+        }
+        if api_key.is_some() == api_key_file.is_some() {
+            return Err("one of `api_key` and `api_key_file` must be given".to_string());
+        }
+        Ok(Self {
+            name,
+            urls,
+            api_key,
+            api_key_file,
+        })
+    }
+}
+
+fn api_key_secret(api_key: Option<&str>, api_key_file: Option<&Path>) -> anyhow::Result<Secret> {
+    match (api_key, api_key_file) {
+        (_, Some(path)) => Secret::from_file(path)
+            .with_context(|| format!("error reading Centrifugo API key file {}", path.display())),
+        (Some(api_key), None) => Ok(Secret::from(api_key)),
+        (None, None) => Err(anyhow::anyhow!(
+            "missing Centrifugo API key, `--centrifugo-api-key` or `--centrifugo-api-key-file` must be given"
+        )),
+    }
+}
+
+/// Returns clients of all configured Centrifugo clusters, the default one first.
+pub(crate) fn clients(config: &Config) -> anyhow::Result<Vec<Client>> {
+    let http = http_client(config)?;
+    let mut clients = vec![Client::with_http(config, http.clone())?];
+    for cluster in &config.centrifugo_cluster {
+        if clients.iter().any(|client| *client.name == cluster.name) {
+            anyhow::bail!("duplicate Centrifugo cluster name `{}`", cluster.name);
+        }
+        let api_key = api_key_secret(cluster.api_key.as_deref(), cluster.api_key_file.as_deref())?;
+        clients.push(Client {
+            name: Arc::from(cluster.name.as_str()),
+            nodes: Node::from_urls(&cluster.urls),
+            failover: FailoverPolicy::new(config),
+            api_key: Arc::new(api_key),
+            http: http.clone(),
+        });
+    }
+    Ok(clients)
+}
+
+fn read_pem(path: &Path, what: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("error reading {what} file {}", path.display()))
+}
+
+fn http_client(config: &Config) -> anyhow::Result<HttpClient> {
+    centrifugo_change_stream::install_crypto_provider();
+    let mut builder = HttpClient::builder()
+        .connect_timeout(config.centrifugo_connect_timeout)
+        .timeout(config.centrifugo_request_timeout);
+    if let Some(path) = &config.centrifugo_tls_ca_file {
+        let certificates = Certificate::from_pem_bundle(&read_pem(path, "Centrifugo CA")?)
+            .context("invalid Centrifugo CA file")?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    match (
+        &config.centrifugo_tls_certificate_file,
+        &config.centrifugo_tls_key_file,
+    ) {
+        (Some(certificate_path), Some(key_path)) => {
+            let mut pem = read_pem(certificate_path, "Centrifugo client certificate")?;
+            pem.push(b'\n');
+            pem.extend(read_pem(key_path, "Centrifugo client key")?);
+            let identity =
+                Identity::from_pem(&pem).context("invalid Centrifugo client certificate or key")?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("both Centrifugo client certificate and key files must be given"),
+    }
+    if let Some(max_idle) = config.centrifugo_pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(idle_timeout) = config.centrifugo_pool_idle_timeout {
+        builder = builder.pool_idle_timeout(idle_timeout);
+    }
+    if config.centrifugo_http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    builder
+        .build()
+        .context("error building Centrifugo HTTP client")
+}
+
+/// Health of a Centrifugo cluster, with information about its nodes if it is reachable.
+#[derive(Clone, Debug)]
+pub(crate) struct ClusterHealth {
+    pub(crate) name: Arc<str>,
+    pub(crate) nodes: Option<Vec<NodeInfo>>,
 }
 
-pub(crate) type HealthChannel = RoundtripSender<(), bool>;
+impl ClusterHealth {
+    pub(crate) fn healthy(&self) -> bool {
+        self.nodes.is_some()
+    }
+}
+
+pub(crate) type HealthChannel = RoundtripSender<(), Vec<ClusterHealth>>;
 
 pub(crate) type TagsUpdateChannel = mpsc::Sender<UpdateEvent>;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
-enum PublishResponse {
-    Result {},
+enum ApiResponse<T> {
+    Result(T),
     Error { code: u16, message: String },
 }
 
+#[derive(Deserialize)]
+struct PublishResult {}
+
+#[derive(Deserialize)]
+struct InfoResult {
+    nodes: Vec<NodeInfo>,
+}
+
+#[derive(Deserialize)]
+struct ChannelsResult {
+    #[serde(default)]
+    channels: HashMap<String, IgnoredAny>,
+}
+
+/// Information about a node of a Centrifugo cluster.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub(crate) struct NodeInfo {
+    pub(crate) name: String,
+    pub(crate) version: String,
+}
+
+#[derive(Clone, Copy)]
+struct FailoverPolicy {
+    failure_threshold: u32,
+    probe_interval: Duration,
+}
+
+impl FailoverPolicy {
+    fn new(config: &Config) -> Self {
+        Self {
+            failure_threshold: config.centrifugo_node_failure_threshold.get(),
+            probe_interval: config.centrifugo_node_probe_interval,
+        }
+    }
+}
+
+#[derive(Default)]
+struct NodeHealth {
+    consecutive_errors: u32,
+    unhealthy_since: Option<Instant>,
+}
+
+/// A Centrifugo node, with its health shared between client clones.
+struct Node {
+    url: Url,
+    health: Mutex<NodeHealth>,
+}
+
+impl Node {
+    fn from_urls(urls: &[Url]) -> Arc<[Self]> {
+        urls.iter()
+            .map(|url| Self {
+                url: url.clone(),
+                health: Mutex::default(),
+            })
+            .collect()
+    }
+
+    /// Returns whether the node is healthy, or unhealthy for long enough to be probed.
+    fn is_available(&self, policy: &FailoverPolicy) -> bool {
+        match self
+            .health
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .unhealthy_since
+        {
+            Some(since) => since.elapsed() >= policy.probe_interval,
+            None => true,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap_or_else(|err| err.into_inner());
+        health.consecutive_errors = 0;
+        if health.unhealthy_since.take().is_some() {
+            info!(msg = "node is healthy again", node = %self.url);
+        }
+    }
+
+    fn record_failure(&self, policy: &FailoverPolicy) {
+        let mut health = self.health.lock().unwrap_or_else(|err| err.into_inner());
+        health.consecutive_errors += 1;
+        if health.unhealthy_since.is_some() {
+            // Failed probe, wait for another interval.
+            health.unhealthy_since = Some(Instant::now());
+        } else if health.consecutive_errors >= policy.failure_threshold {
+            health.unhealthy_since = Some(Instant::now());
+            error!(kind = "node marked unhealthy", node = %self.url, errors = health.consecutive_errors);
+        }
+    }
+}
+
+/// API call error, telling whether it is caused by the node.
+enum CallError {
+    Node,
+    Centrifugo,
+}
+
 #[derive(Clone)]
 pub(crate) struct Client {
-    base_url: Url,
-    api_key: Arc<str>,
+    name: Arc<str>,
+    nodes: Arc<[Node]>,
+    failover: FailoverPolicy,
+    api_key: Arc<Secret>,
     http: HttpClient,
 }
 
 impl Client {
-    pub(crate) fn new(config: &Config) -> Self {
-        let base_url = config.centrifugo_url.clone();
-        let api_key = Arc::from(config.centrifugo_api_key.as_str());
-        let http = HttpClient::new();
+    fn with_http(config: &Config, http: HttpClient) -> anyhow::Result<Self> {
+        let api_key = api_key_secret(
+            config.centrifugo_api_key.as_deref(),
+            config.centrifugo_api_key_file.as_deref(),
+        )?;
 
-        Self {
-            base_url,
-            api_key,
+        Ok(Self {
+            name: Arc::from(DEFAULT_CLUSTER),
+            nodes: Node::from_urls(&config.centrifugo_url),
+            failover: FailoverPolicy::new(config),
+            api_key: Arc::new(api_key),
             http,
-        }
+        })
     }
 
-    #[instrument(name = "centrifugo_publish", skip_all)]
-    async fn publish(&self, channel: &str, data: impl Serialize) -> Result<(), ()> {
-        let url = self.base_url.join("/api/publish").unwrap();
+    /// Returns nodes in order of preference, available ones first.
+    fn candidates(&self) -> impl Iterator<Item = &Node> {
+        let (available, unavailable): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .partition(|node| node.is_available(&self.failover));
+        available.into_iter().chain(unavailable)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Publishes data to a channel.
+    #[instrument(name = "centrifugo_publish", skip_all, fields(cluster = %self.name))]
+    pub(crate) async fn publish(&self, channel: &str, data: impl Serialize) -> Result<(), ()> {
         let json = json!({
             "channel": channel,
             "data": data,
         });
         debug!(%json);
 
+        self.call::<PublishResult>("publish", &json)
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns information about the nodes of the cluster.
+    #[instrument(name = "centrifugo_info", skip_all, fields(cluster = %self.name))]
+    pub(crate) async fn info(&self) -> Result<Vec<NodeInfo>, ()> {
+        self.call::<InfoResult>("info", &json!({}))
+            .await
+            .map(|result| result.nodes)
+    }
+
+    /// Returns the active channels matching a pattern, i.e. the ones having subscribers.
+    #[instrument(name = "centrifugo_channels", skip_all, fields(cluster = %self.name))]
+    pub(crate) async fn channels(&self, pattern: &str) -> Result<Vec<String>, ()> {
+        self.call::<ChannelsResult>("channels", &json!({ "pattern": pattern }))
+            .await
+            .map(|result| result.channels.into_keys().collect())
+    }
+
+    /// Calls an API method on the first available node, failing over to the next ones on node
+    /// errors.
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: &Value) -> Result<T, ()> {
+        let api_key = self.api_key.get().map_err(|err| {
+            error!(kind = "reading API key", %err);
+        })?;
+
+        for node in self.candidates() {
+            match self.call_node(node, &api_key, method, params).await {
+                Ok(result) => {
+                    node.record_success();
+                    return Ok(result);
+                }
+                Err(CallError::Centrifugo) => {
+                    node.record_success();
+                    return Err(());
+                }
+                Err(CallError::Node) => node.record_failure(&self.failover),
+            }
+        }
+
+        Err(())
+    }
+
+    #[instrument(skip_all, fields(node = %node.url))]
+    async fn call_node<T: DeserializeOwned>(
+        &self,
+        node: &Node,
+        api_key: &str,
+        method: &str,
+        params: &Value,
+    ) -> Result<T, CallError> {
+        let url = node.url.join("/api/").unwrap().join(method).unwrap();
+
         let resp = self
             .http
             .post(url)
-            .header("X-API-Key", self.api_key.as_ref())
-            .json(&json)
+            .header("X-API-Key", api_key)
+            .json(params)
             .send()
             .await
             .map_err(|err| {
                 error!(kind = "request sending", %err);
+                CallError::Node
             })?;
 
         let status_code = resp.status();
         if !status_code.is_success() {
             error!(kind = "bad status code", %status_code);
-            return Err(());
+            return Err(if status_code.is_server_error() {
+                CallError::Node
+            } else {
+                CallError::Centrifugo
+            });
         }
 
-        let response: PublishResponse = resp.json().await.map_err(|err| {
+        let response: ApiResponse<T> = resp.json().await.map_err(|err| {
             error!(kind = "response deserialization", %err);
+            CallError::Node
         })?;
 
-        if let PublishResponse::Error { code, message } = response {
-            error!(kind = "Centrifugo error", code, message);
-            return Err(());
+        match response {
+            ApiResponse::Result(result) => Ok(result),
+            ApiResponse::Error { code, message } => {
+                error!(kind = "Centrifugo error", code, message);
+                Err(CallError::Centrifugo)
+            }
         }
+    }
+}
 
-        Ok(())
+/// Publication queued for a cluster, with the deliveries of the changes it carries.
+struct QueuedPublication {
+    channel: String,
+    data: Value,
+    tickets: Vec<Ticket>,
+}
+
+/// Number of queued items of a queue, along with its capacity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct QueueLevel {
+    pub(crate) length: usize,
+    pub(crate) capacity: usize,
+}
+
+impl<T> From<&mpsc::Sender<T>> for QueueLevel {
+    fn from(tx: &mpsc::Sender<T>) -> Self {
+        Self {
+            length: tx.max_capacity() - tx.capacity(),
+            capacity: tx.max_capacity(),
+        }
     }
+}
 
-    pub(crate) fn handle_tags_update(&self, buffer: usize) -> (TagsUpdateChannel, JoinHandle<()>) {
-        let (tx, mut rx) = mpsc::channel::<UpdateEvent>(buffer);
-        let cloned_self = self.clone();
+/// Levels of the tags update queue and of cluster publication queues.
+#[derive(Clone, Debug)]
+pub(crate) struct QueueLevels {
+    pub(crate) tags_update: QueueLevel,
+    pub(crate) clusters: Vec<(Arc<str>, QueueLevel)>,
+}
 
+pub(crate) type QueuesChannel = RoundtripSender<(), QueueLevels>;
+
+/// Publication queue of a Centrifugo cluster, so that a slow cluster does not delay the others.
+struct ClusterQueue {
+    name: Arc<str>,
+    tx: mpsc::Sender<QueuedPublication>,
+    task: JoinHandle<()>,
+    deliveries: Deliveries,
+}
+
+impl ClusterQueue {
+    /// Spawns the queue of a cluster, sending publications once the task of the queue it
+    /// replaces, if any, has finished sending its own, so that their order is kept.
+    fn spawn(
+        client: Client,
+        buffer: usize,
+        deliveries: Deliveries,
+        abort_token: CancellationToken,
+        replaced: Option<JoinHandle<()>>,
+    ) -> Self {
+        let name = Arc::clone(&client.name);
+        let task_deliveries = deliveries.clone();
+        let (tx, mut rx) = mpsc::channel::<QueuedPublication>(buffer);
         let task = tokio::spawn(
             async move {
+                if let Some(replaced) = replaced
+                    && let Err(err) = replaced.await
+                {
+                    error!(kind = "cluster queue joining", %err);
+                }
                 info!(status = "started");
 
-                while let Some(update_event) = rx.recv().await {
-                    let (channel, data) = update_event.into_centrifugo();
-                    let _ = cloned_self.publish(&channel, data).await;
+                while let Some(publication) = rx.recv().await {
+                    tokio::select! {
+                        published = client.publish(&publication.channel, &publication.data) => {
+                            if published.is_err() {
+                                task_deliveries.fail(&publication.tickets);
+                            }
+                        }
+                        _ = abort_token.cancelled() => {
+                            task_deliveries.fail(&publication.tickets);
+                            rx.close();
+                            while let Some(dropped) = rx.recv().await {
+                                task_deliveries.fail(&dropped.tickets);
+                            }
+                            break;
+                        }
+                    }
                 }
 
                 info!(status = "terminating");
             }
-            .instrument(info_span!("centrifugo_tags_update_handler")),
+            .instrument(info_span!("centrifugo_cluster_queue", cluster = %name)),
         );
+        Self {
+            name,
+            tx,
+            task,
+            deliveries,
+        }
+    }
 
-        (tx, task)
+    /// Queues a publication, dropping it if the queue is full, so that publishing to other
+    /// clusters is not delayed.
+    fn push(&self, channel: &str, data: &Value, tickets: &[Ticket]) {
+        let publication = QueuedPublication {
+            channel: channel.to_string(),
+            data: data.clone(),
+            tickets: tickets.to_vec(),
+        };
+        if let Err(err) = self.tx.try_send(publication) {
+            error!(kind = "cluster queue sending", cluster = %self.name, %err);
+            self.deliveries.fail(&err.into_inner().tickets);
+        }
     }
+}
 
-    pub(crate) fn handle_health(&self) -> (HealthChannel, JoinHandle<()>) {
-        let (tx, mut rx) = roundtrip_channel(1);
-        let cloned_self = self.clone();
+/// Returns the publications of an update event, transformed by the script if any.
+fn publications(
+    settings: &Settings,
+    channel: String,
+    publication: &Publication,
+) -> Result<Vec<(String, Value)>, String> {
+    match &settings.transform {
+        Some(transform) => transform.publications(channel, publication, &publication.change()),
+        None => serde_json::to_value(publication)
+            .map(|data| vec![(channel, data)])
+            .map_err(|err| err.to_string()),
+    }
+}
 
-        let task = tokio::spawn(
-            async move {
-                info!(status = "started");
+/// Returns the channels matching a pattern which have subscribers on any cluster, along with
+/// the names of clusters which could not be queried.
+pub(crate) async fn active_channels(
+    settings: &Settings,
+    pattern: &str,
+) -> (HashSet<String>, Vec<String>) {
+    let outcomes = join_all(
+        settings
+            .centrifugo_clients
+            .iter()
+            .map(|client| async { (client.name(), client.channels(pattern).await) }),
+    )
+    .await;
+    let mut channels = HashSet::new();
+    let mut failed = Vec::new();
+    for (name, outcome) in outcomes {
+        match outcome {
+            Ok(cluster_channels) => channels.extend(cluster_channels),
+            Err(()) => failed.push(name.to_string()),
+        }
+    }
+    (channels, failed)
+}
 
-                while let Some((_, response_tx)) = rx.recv().await {
-                    let outcome = cloned_self.publish("_", ()).await.is_ok();
-                    if response_tx.send(outcome).is_err() {
-                        error!(kind = "response channel sending");
+/// Publishes an update event to all clusters, waiting for publications to be sent.
+///
+/// Unlike the tags update handler, this does not throttle publications nor number their
+/// versions. Returns the number of failed publications.
+pub(crate) async fn publish_event(
+    settings: &Settings,
+    filter: &mut Filter,
+    update_event: UpdateEvent,
+) -> usize {
+    let Some((channel, publication)) = update_event.into_centrifugo(filter) else {
+        debug!(msg = "nothing to publish after filtering");
+        return 0;
+    };
+    let publications = match publications(settings, channel.clone(), &publication) {
+        Ok(publications) => publications,
+        Err(err) => {
+            error!(kind = "transform script", %err);
+            filter.unpublished(&channel);
+            return 1;
+        }
+    };
+    filter.published(&channel);
+    let mut failures = 0;
+    for (channel, data) in &publications {
+        let outcomes = join_all(
+            settings
+                .centrifugo_clients
+                .iter()
+                .map(|client| client.publish(channel, data)),
+        )
+        .await;
+        failures += outcomes.iter().filter(|outcome| outcome.is_err()).count();
+    }
+    failures
+}
+
+/// Versions of the last publications on channels, numbering publications in full mode.
+#[derive(Clone, Default)]
+pub(crate) struct Versions(Arc<Mutex<HashMap<String, u64>>>);
+
+impl Versions {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns the version of the last publication on the channel, 0 if none.
+    pub(crate) fn current(&self, channel: &str) -> u64 {
+        self.lock().get(channel).copied().unwrap_or_default()
+    }
+
+    fn set(&self, channel: String, version: u64) {
+        self.lock().insert(channel, version);
+    }
+
+    fn retain(&self, mut f: impl FnMut(&str) -> bool) {
+        self.lock().retain(|channel, _| f(channel));
+    }
+
+    /// Returns known versions of channels.
+    #[cfg(test)]
+    pub(crate) fn known(versions: &[(&str, u64)]) -> Self {
+        let known = Self::default();
+        for (channel, version) in versions {
+            known.set(channel.to_string(), *version);
+        }
+        known
+    }
+}
+
+/// Publishes update events to all Centrifugo clusters.
+struct Publisher {
+    /// Versions of channels, in full publication mode.
+    versions: Option<Versions>,
+    buffer: usize,
+    /// Refresh of active channels after which versions and deadbands were last pruned.
+    pruned_refresh: u64,
+    queues: Vec<ClusterQueue>,
+    /// Tasks of queues replaced by a settings reload, still sending their queued publications.
+    retired: Vec<JoinHandle<()>>,
+    deliveries: Deliveries,
+    active_channels: ActiveChannels,
+    /// Cancelled when the drain deadline is reached on shutdown.
+    abort_token: CancellationToken,
+}
+
+impl Publisher {
+    fn new(
+        settings: &Settings,
+        versions: Option<Versions>,
+        buffer: usize,
+        deliveries: Deliveries,
+        active_channels: ActiveChannels,
+    ) -> Self {
+        let mut publisher = Self {
+            versions,
+            buffer,
+            pruned_refresh: 0,
+            queues: Vec::new(),
+            retired: Vec::new(),
+            deliveries,
+            active_channels,
+            abort_token: CancellationToken::new(),
+        };
+        publisher.reload(settings);
+        publisher
+    }
+
+    /// Replaces cluster queues with ones using clients of the settings.
+    ///
+    /// The queue of a cluster kept by the settings starts sending once the replaced one has sent
+    /// its queued publications.
+    fn reload(&mut self, settings: &Settings) {
+        self.retired.retain(|task| !task.is_finished());
+        let mut replaced = std::mem::take(&mut self.queues)
+            .into_iter()
+            .map(|queue| (queue.name, queue.task))
+            .collect::<HashMap<_, _>>();
+        self.queues = settings
+            .centrifugo_clients
+            .iter()
+            .map(|client| {
+                ClusterQueue::spawn(
+                    client.clone(),
+                    self.buffer,
+                    self.deliveries.clone(),
+                    self.abort_token.clone(),
+                    replaced.remove(&client.name),
+                )
+            })
+            .collect();
+        self.retired.extend(replaced.into_values());
+    }
+
+    /// Queues the update event to all clusters, after version numbering and transformation.
+    ///
+    /// Publications on channels without subscribers are skipped; if none is left, neither the
+    /// version nor the deadband references of the channel advance.
+    fn publish(
+        &mut self,
+        settings: &Settings,
+        filter: &mut Filter,
+        channel: String,
+        mut publication: Publication,
+    ) {
+        self.prune(settings, filter);
+        let version = self
+            .versions
+            .as_ref()
+            .map(|versions| versions.current(&channel) + 1);
+        if let Some(version) = version {
+            publication.set_version(version);
+        }
+        let publications = match publications(settings, channel.clone(), &publication) {
+            Ok(publications) => publications,
+            Err(err) => {
+                error!(kind = "transform script", %err);
+                filter.unpublished(&channel);
+                return;
+            }
+        };
+        let mut published = false;
+        for (output, data) in &publications {
+            if !self.active_channels.is_active(output) {
+                debug!(msg = "no subscriber", channel = output);
+                self.active_channels.record_skipped();
+                continue;
+            }
+            for queue in &self.queues {
+                queue.push(output, data, publication.tickets());
+            }
+            published = true;
+        }
+        if !published {
+            filter.unpublished(&channel);
+            return;
+        }
+        filter.published(&channel);
+        if let (Some(versions), Some(version)) = (&self.versions, version) {
+            versions.set(channel, version);
+        }
+    }
+
+    /// Forgets versions and deadband references of channels without subscribers, once per
+    /// refresh of active channels, so that they do not grow with every channel ever published.
+    ///
+    /// A client subscribing afterwards gets the version restarted from 0 in its initial data.
+    /// Nothing is pruned with a transform script, whose output channels may differ from the
+    /// channels of documents.
+    fn prune(&mut self, settings: &Settings, filter: &mut Filter) {
+        let refresh = self.active_channels.refreshes();
+        if refresh == self.pruned_refresh || settings.transform.is_some() {
+            return;
+        }
+        self.pruned_refresh = refresh;
+        let is_active = |channel: &str| self.active_channels.is_active(channel);
+        if let Some(versions) = &self.versions {
+            versions.retain(is_active);
+        }
+        filter.retain_channels(is_active);
+    }
+
+    fn queue_levels(&self) -> Vec<(Arc<str>, QueueLevel)> {
+        self.queues
+            .iter()
+            .map(|queue| (Arc::clone(&queue.name), QueueLevel::from(&queue.tx)))
+            .collect()
+    }
+
+    /// Waits for queued publications to be sent, until the timeout.
+    async fn shutdown(self, timeout: Duration) {
+        let tasks = self.queues.into_iter().map(|queue| queue.task);
+        let tasks = tasks.chain(self.retired).collect::<Vec<_>>();
+        let abort_token = self.abort_token;
+        let deadline = tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            error!(
+                kind = "drain deadline reached",
+                reaction = "dropping queued publications"
+            );
+            abort_token.cancel();
+        });
+        for task in tasks {
+            if let Err(err) = task.await {
+                error!(kind = "cluster queue joining", %err);
+            }
+        }
+        deadline.abort();
+    }
+}
+
+/// Outcome of the tags update handler, once drained.
+pub(crate) struct Drained {
+    /// Resume token of the last change which, as all previous ones, has been delivered.
+    pub(crate) resume_token: Option<ResumeToken>,
+    /// Number of publications which could not be delivered (failed or dropped).
+    pub(crate) undelivered: usize,
+}
+
+pub(crate) fn handle_tags_update(
+    mut settings_rx: SettingsReceiver,
+    buffer: usize,
+    versions: Option<Versions>,
+    active_channels: ActiveChannels,
+    drain_timeout: Duration,
+    deliveries: Deliveries,
+) -> (TagsUpdateChannel, QueuesChannel, JoinHandle<Drained>) {
+    let (tx, mut rx) = mpsc::channel::<UpdateEvent>(buffer);
+    let (queues_tx, mut queues_rx) = roundtrip_channel(1);
+
+    let task = tokio::spawn(
+        async move {
+            info!(status = "started");
+
+            let mut settings = settings_rx.borrow_and_update().clone();
+            let mut filter = Filter::new(&settings.filter);
+            let mut throttle = Throttle::new(&settings.throttle);
+            let mut publisher = Publisher::new(
+                &settings,
+                versions,
+                buffer,
+                deliveries.clone(),
+                active_channels,
+            );
+
+            loop {
+                tokio::select! {
+                    // Settings changes apply to events received afterwards.
+                    biased;
+
+                    Ok(()) = settings_rx.changed() => {
+                        for (channel, publication) in throttle.drain() {
+                            publisher.publish(&settings, &mut filter, channel, publication);
+                        }
+                        settings = settings_rx.borrow_and_update().clone();
+                        filter.reload(&settings.filter);
+                        throttle = Throttle::new(&settings.throttle);
+                        publisher.reload(&settings);
+                        info!(msg = "settings reloaded");
+                    }
+                    received = rx.recv() => {
+                        let Some(mut update_event) = received else {
+                            break;
+                        };
+                        let ticket = update_event.take_ticket();
+                        let Some((channel, mut publication)) =
+                            update_event.into_centrifugo(&mut filter)
+                        else {
+                            debug!(msg = "nothing to publish after filtering");
+                            continue;
+                        };
+                        if let Some(ticket) = ticket {
+                            publication.attach(ticket);
+                        }
+                        if let Some((channel, publication)) = throttle.admit(channel, publication) {
+                            publisher.publish(&settings, &mut filter, channel, publication);
+                        }
+                    }
+                    _ = throttle.tick() => {
+                        for (channel, publication) in throttle.flush() {
+                            publisher.publish(&settings, &mut filter, channel, publication);
+                        }
+                    }
+                    Some((_, response_tx)) = queues_rx.recv() => {
+                        let levels = QueueLevels {
+                            tags_update: QueueLevel {
+                                length: rx.len(),
+                                capacity: rx.max_capacity(),
+                            },
+                            clusters: publisher.queue_levels(),
+                        };
+                        if response_tx.send(levels).is_err() {
+                            error!(kind = "response channel sending");
+                        }
                     }
                 }
+            }
 
-                info!(status = "terminating");
+            for (channel, publication) in throttle.drain() {
+                publisher.publish(&settings, &mut filter, channel, publication);
             }
-            .instrument(info_span!("centrifugo_health_handler")),
-        );
+            drop(rx);
+            publisher.shutdown(drain_timeout).await;
+            let undelivered = deliveries.failures();
 
-        (tx, task)
-    }
+            info!(status = "terminating", undelivered);
+            Drained {
+                resume_token: deliveries.delivered(),
+                undelivered,
+            }
+        }
+        .instrument(info_span!("centrifugo_tags_update_handler")),
+    );
+
+    (tx, queues_tx, task)
+}
+
+pub(crate) fn handle_health(mut settings_rx: SettingsReceiver) -> (HealthChannel, JoinHandle<()>) {
+    let (tx, mut rx) = roundtrip_channel(1);
+
+    let task = tokio::spawn(
+        async move {
+            info!(status = "started");
+
+            let mut cached: Option<(Instant, Vec<ClusterHealth>)> = None;
+
+            while let Some((_, response_tx)) = rx.recv().await {
+                if settings_rx.has_changed().unwrap_or_default() {
+                    cached = None;
+                }
+                let settings = settings_rx.borrow_and_update().clone();
+                let outcome = match &cached {
+                    Some((checked_at, outcome))
+                        if checked_at.elapsed() < settings.centrifugo_health_cache_ttl =>
+                    {
+                        debug!(msg = "using cached outcome");
+                        outcome.clone()
+                    }
+                    _ => {
+                        let outcome =
+                            join_all(settings.centrifugo_clients.iter().map(|client| async {
+                                ClusterHealth {
+                                    name: Arc::clone(&client.name),
+                                    nodes: client.info().await.ok(),
+                                }
+                            }))
+                            .await;
+                        cached = Some((Instant::now(), outcome.clone()));
+                        outcome
+                    }
+                };
+                if response_tx.send(outcome).is_err() {
+                    error!(kind = "response channel sending");
+                }
+            }
+
+            info!(status = "terminating");
+        }
+        .instrument(info_span!("centrifugo_health_handler")),
+    );
+
+    (tx, task)
 }
 
 #[cfg(test)]
@@ -143,8 +1028,43 @@ mod tests {
     use super::*;
 
     mod client {
+        use crate::settings::testing::try_settings;
+
         use super::*;
 
+        /// Returns the client of the default cluster, with settings parsed from given command
+        /// line arguments.
+        fn client(args: &[&str]) -> anyhow::Result<Client> {
+            let settings = try_settings(args)?;
+            Ok(settings.centrifugo_clients[0].clone())
+        }
+
+        #[test]
+        fn missing_api_key_file() {
+            assert!(client(&["--centrifugo-api-key-file", "/nonexistent"]).is_err());
+        }
+
+        #[test]
+        fn duplicate_cluster_name() {
+            assert!(
+                client(&[
+                    "--centrifugo-cluster",
+                    "name=default,url=http://other:8000,api_key=otherkey",
+                ])
+                .is_err()
+            );
+        }
+
+        #[test]
+        fn missing_tls_key_file() {
+            assert!(client(&["--centrifugo-tls-certificate-file", "/cert.pem",]).is_err());
+        }
+
+        #[test]
+        fn missing_tls_ca_file() {
+            assert!(client(&["--centrifugo-tls-ca-file", "/nonexistent.pem",]).is_err());
+        }
+
         mod publish {
             use mockito::{Mock, Server};
 
@@ -161,11 +1081,34 @@ mod tests {
             #[tokio::test]
             async fn request_send_failure() {
                 let server = Server::new_async().await;
-                let config = Config {
-                    centrifugo_url: server.url().parse().unwrap(),
-                    centrifugo_api_key: "\0".to_string(),
-                };
-                let client = Client::new(&config);
+                let client = client(&[
+                    "--centrifugo-url",
+                    &server.url(),
+                    "--centrifugo-api-key",
+                    "\0",
+                ])
+                .unwrap();
+                let result = client.publish("somechannel", "somedata").await;
+                assert!(result.is_err());
+            }
+
+            #[tokio::test]
+            async fn request_timeout() {
+                let mut server = Server::new_async().await;
+                let _mock = server_mock(&mut server)
+                    .with_body_from_request(|_| {
+                        std::thread::sleep(Duration::from_millis(500));
+                        br#"{"result":{}}"#.to_vec()
+                    })
+                    .create_async()
+                    .await;
+                let client = client(&[
+                    "--centrifugo-url",
+                    &server.url(),
+                    "--centrifugo-request-timeout",
+                    "100ms",
+                ])
+                .unwrap();
                 let result = client.publish("somechannel", "somedata").await;
                 assert!(result.is_err());
             }
@@ -177,11 +1120,7 @@ mod tests {
                     .with_status(500)
                     .create_async()
                     .await;
-                let config = Config {
-                    centrifugo_url: server.url().parse().unwrap(),
-                    centrifugo_api_key: "somekey".to_string(),
-                };
-                let client = Client::new(&config);
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
                 let result = client.publish("somechannel", "somedata").await;
                 mock.assert_async().await;
                 assert!(result.is_err());
@@ -194,11 +1133,7 @@ mod tests {
                     .with_body(r#"{"unknown":null}"#)
                     .create_async()
                     .await;
-                let config = Config {
-                    centrifugo_url: server.url().parse().unwrap(),
-                    centrifugo_api_key: "somekey".to_string(),
-                };
-                let client = Client::new(&config);
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
                 let result = client.publish("somechannel", "somedata").await;
                 mock.assert_async().await;
                 assert!(result.is_err());
@@ -211,11 +1146,7 @@ mod tests {
                     .with_body(r#"{"error":{"code":42,"message":"a message"}}"#)
                     .create_async()
                     .await;
-                let config = Config {
-                    centrifugo_url: server.url().parse().unwrap(),
-                    centrifugo_api_key: "somekey".to_string(),
-                };
-                let client = Client::new(&config);
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
                 let result = client.publish("somechannel", "somedata").await;
                 mock.assert_async().await;
                 assert!(result.is_err());
@@ -228,15 +1159,930 @@ mod tests {
                     .with_body(r#"{"result":{}}"#)
                     .create_async()
                     .await;
-                let config = Config {
-                    centrifugo_url: server.url().parse().unwrap(),
-                    centrifugo_api_key: "somekey".to_string(),
-                };
-                let client = Client::new(&config);
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
+                let result = client.publish("somechannel", "somedata").await;
+                mock.assert_async().await;
+                assert!(result.is_ok());
+            }
+
+            #[tokio::test]
+            async fn api_key_file() {
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_body(r#"{"result":{}}"#)
+                    .create_async()
+                    .await;
+                let path = std::env::temp_dir().join(format!(
+                    "centrifugo-change-stream-api-key-tests-{}",
+                    std::process::id()
+                ));
+                std::fs::write(&path, "somekey\n").unwrap();
+                let client = client(&[
+                    "--centrifugo-url",
+                    &server.url(),
+                    "--centrifugo-api-key-file",
+                    path.to_str().unwrap(),
+                ])
+                .unwrap();
                 let result = client.publish("somechannel", "somedata").await;
+                std::fs::remove_file(&path).unwrap();
                 mock.assert_async().await;
                 assert!(result.is_ok());
             }
+
+            #[tokio::test]
+            async fn failover() {
+                let mut failing = Server::new_async().await;
+                let failing_mock = server_mock(&mut failing)
+                    .with_status(503)
+                    .expect(2)
+                    .create_async()
+                    .await;
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_body(r#"{"result":{}}"#)
+                    .expect(3)
+                    .create_async()
+                    .await;
+                let client = client(&[
+                    "--centrifugo-url",
+                    &format!("{},{}", failing.url(), server.url()),
+                    "--centrifugo-node-failure-threshold",
+                    "2",
+                ])
+                .unwrap();
+                for _ in 0..3 {
+                    let result = client.publish("somechannel", "somedata").await;
+                    assert!(result.is_ok());
+                }
+                failing_mock.assert_async().await;
+                mock.assert_async().await;
+            }
+
+            #[tokio::test]
+            async fn centrifugo_error_no_failover() {
+                let mut server = Server::new_async().await;
+                let mock = server_mock(&mut server)
+                    .with_body(r#"{"error":{"code":102,"message":"unknown channel"}}"#)
+                    .create_async()
+                    .await;
+                let mut other = Server::new_async().await;
+                let other_mock = server_mock(&mut other).expect(0).create_async().await;
+                let client = client(&[
+                    "--centrifugo-url",
+                    &format!("{},{}", server.url(), other.url()),
+                ])
+                .unwrap();
+                let result = client.publish("somechannel", "somedata").await;
+                mock.assert_async().await;
+                other_mock.assert_async().await;
+                assert!(result.is_err());
+            }
+
+            #[tokio::test]
+            async fn probe_unhealthy_node() {
+                let mut server = Server::new_async().await;
+                let client = client(&[
+                    "--centrifugo-url",
+                    &server.url(),
+                    "--centrifugo-node-failure-threshold",
+                    "1",
+                    "--centrifugo-node-probe-interval",
+                    "100ms",
+                ])
+                .unwrap();
+                let failing_mock = server_mock(&mut server)
+                    .with_status(503)
+                    .create_async()
+                    .await;
+                assert!(client.publish("somechannel", "somedata").await.is_err());
+                failing_mock.remove_async().await;
+                assert!(!client.nodes[0].is_available(&client.failover));
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                assert!(client.nodes[0].is_available(&client.failover));
+                let mock = server_mock(&mut server)
+                    .with_body(r#"{"result":{}}"#)
+                    .create_async()
+                    .await;
+                assert!(client.publish("somechannel", "somedata").await.is_ok());
+                mock.assert_async().await;
+                assert!(
+                    client.nodes[0]
+                        .health
+                        .lock()
+                        .unwrap()
+                        .unhealthy_since
+                        .is_none()
+                );
+            }
+        }
+
+        mod channels {
+            use mockito::Server;
+
+            use super::*;
+
+            #[tokio::test]
+            async fn active_channels() {
+                let mut server = Server::new_async().await;
+                let mock = server
+                    .mock("POST", "/api/channels")
+                    .match_header("X-API-Key", "somekey")
+                    .match_body(r#"{"pattern":"db.coll:*"}"#)
+                    .with_body(
+                        r#"{"result":{"channels":{
+                            "db.coll:first":{"num_clients":1},
+                            "db.coll:second":{"num_clients":3}
+                        }}}"#,
+                    )
+                    .create_async()
+                    .await;
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
+                let mut channels = client.channels("db.coll:*").await.unwrap();
+                channels.sort();
+                assert_eq!(channels, ["db.coll:first", "db.coll:second"]);
+                mock.assert_async().await;
+            }
+
+            #[tokio::test]
+            async fn no_active_channel() {
+                let mut server = Server::new_async().await;
+                let _mock = server
+                    .mock("POST", "/api/channels")
+                    .with_body(r#"{"result":{}}"#)
+                    .create_async()
+                    .await;
+                let client = client(&["--centrifugo-url", &server.url()]).unwrap();
+                assert!(client.channels("db.coll:*").await.unwrap().is_empty());
+            }
+        }
+    }
+
+    mod cluster_queue {
+        use mockito::Server;
+        use serde_json::json;
+        use tokio::sync::oneshot;
+
+        use crate::settings::testing::settings;
+
+        use super::*;
+
+        fn queue(tx: mpsc::Sender<QueuedPublication>) -> ClusterQueue {
+            ClusterQueue {
+                name: Arc::from("test"),
+                tx,
+                task: tokio::spawn(async {}),
+                deliveries: Deliveries::new(None),
+            }
+        }
+
+        #[tokio::test]
+        async fn full() {
+            let (tx, mut rx) = mpsc::channel(1);
+            let queue = queue(tx);
+            queue.push("first", &json!(1), &[]);
+            queue.push("second", &json!(2), &[]);
+            assert_eq!(queue.deliveries.failures(), 1);
+            drop(queue);
+            assert_eq!(rx.recv().await.unwrap().channel, "first");
+            assert!(rx.recv().await.is_none());
+        }
+
+        #[tokio::test]
+        async fn after_replaced() {
+            let mut server = Server::new_async().await;
+            let mock = server
+                .mock("POST", "/api/publish")
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (release_tx, release_rx) = oneshot::channel::<()>();
+            let replaced = tokio::spawn(async {
+                let _ = release_rx.await;
+            });
+            let queue = ClusterQueue::spawn(
+                settings.centrifugo_clients[0].clone(),
+                1,
+                Deliveries::new(None),
+                CancellationToken::new(),
+                Some(replaced),
+            );
+
+            queue.push("chan", &json!(1), &[]);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(!mock.matched_async().await);
+            release_tx.send(()).unwrap();
+            let ClusterQueue { tx, task, .. } = queue;
+            drop(tx);
+            task.await.unwrap();
+            mock.assert_async().await;
+        }
+    }
+
+    mod publish_event {
+        use mockito::Server;
+        use serde_json::json;
+
+        use crate::settings::testing::settings;
+
+        use super::*;
+
+        fn update_event(fields: Value) -> UpdateEvent {
+            serde_json::from_value(json!({
+                "ns": { "db": "db", "coll": "coll" },
+                "documentKey": { "_id": "doc" },
+                "updateDescription": { "updatedFields": fields },
+            }))
+            .unwrap()
+        }
+
+        #[tokio::test]
+        async fn all_clusters() {
+            let mut first_server = Server::new_async().await;
+            let first_mock = first_server
+                .mock("POST", "/api/publish")
+                .match_body(r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":1}}}"#)
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let second_server = Server::new_async().await;
+            let cluster = format!("name=second,url={},api_key=otherkey", second_server.url());
+            let settings = settings(&[
+                "--centrifugo-url",
+                &first_server.url(),
+                "--centrifugo-cluster",
+                &cluster,
+            ]);
+            let mut filter = Filter::new(&settings.filter);
+            let failures = publish_event(
+                &settings,
+                &mut filter,
+                update_event(json!({ "val.first": 1 })),
+            )
+            .await;
+            first_mock.assert_async().await;
+            assert_eq!(failures, 1);
+        }
+
+        #[tokio::test]
+        async fn filtered_out() {
+            let server = Server::new_async().await;
+            let settings = settings(&[
+                "--centrifugo-url",
+                &server.url(),
+                "--filter-include-fields",
+                "first",
+            ]);
+            let mut filter = Filter::new(&settings.filter);
+            let failures = publish_event(
+                &settings,
+                &mut filter,
+                update_event(json!({ "val.second": 1 })),
+            )
+            .await;
+            assert_eq!(failures, 0);
+        }
+    }
+
+    mod handle_health {
+        use mockito::{Mock, Server};
+        use tokio::sync::watch;
+
+        use crate::settings::testing::settings;
+
+        use super::*;
+
+        async fn info_mock(server: &mut Server, hits: usize) -> Mock {
+            server
+                .mock("POST", "/api/info")
+                .match_header("X-API-Key", "somekey")
+                .match_body("{}")
+                .with_body(
+                    r#"{"result":{"nodes":[
+                        {"uid":"a","name":"node1","version":"6.1.0","num_clients":1},
+                        {"uid":"b","name":"node2","version":"6.1.0","num_clients":2}
+                    ]}}"#,
+                )
+                .expect(hits)
+                .create_async()
+                .await
+        }
+
+        #[tokio::test]
+        async fn info() {
+            let mut server = Server::new_async().await;
+            let mock = info_mock(&mut server, 1).await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, task) = handle_health(settings_rx);
+
+            let outcome = tx.roundtrip(()).await.unwrap();
+            assert_eq!(outcome.len(), 1);
+            assert_eq!(outcome[0].name.as_ref(), DEFAULT_CLUSTER);
+            let nodes = outcome[0].nodes.as_ref().unwrap();
+            assert_eq!(
+                nodes
+                    .iter()
+                    .map(|node| node.name.as_str())
+                    .collect::<Vec<_>>(),
+                ["node1", "node2"]
+            );
+            assert_eq!(nodes[0].version, "6.1.0");
+            mock.assert_async().await;
+            drop(tx);
+            task.await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn unreachable() {
+            let server = Server::new_async().await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, _) = handle_health(settings_rx);
+
+            let outcome = tx.roundtrip(()).await.unwrap();
+            assert!(!outcome[0].healthy());
+        }
+
+        #[tokio::test]
+        async fn cached_outcome() {
+            let mut server = Server::new_async().await;
+            let mock = info_mock(&mut server, 1).await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, _) = handle_health(settings_rx);
+
+            for _ in 0..3 {
+                assert!(tx.roundtrip(()).await.unwrap()[0].healthy());
+            }
+            mock.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn cache_disabled() {
+            let mut server = Server::new_async().await;
+            let mock = info_mock(&mut server, 2).await;
+            let settings = settings(&[
+                "--centrifugo-url",
+                &server.url(),
+                "--centrifugo-health-cache-ttl",
+                "0s",
+            ]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, _) = handle_health(settings_rx);
+
+            for _ in 0..2 {
+                assert!(tx.roundtrip(()).await.unwrap()[0].healthy());
+            }
+            mock.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn settings_reload() {
+            let mut server = Server::new_async().await;
+            let mock = info_mock(&mut server, 2).await;
+            let settings_args = ["--centrifugo-url", &server.url()];
+            let (settings_tx, settings_rx) = watch::channel(Arc::new(settings(&settings_args)));
+            let (tx, _) = handle_health(settings_rx);
+
+            assert!(tx.roundtrip(()).await.unwrap()[0].healthy());
+            settings_tx.send_replace(Arc::new(settings(&settings_args)));
+            assert!(tx.roundtrip(()).await.unwrap()[0].healthy());
+            mock.assert_async().await;
+        }
+    }
+
+    mod handle_tags_update {
+        use mockito::Server;
+        use serde_json::json;
+        use tokio::sync::watch;
+
+        use crate::script::Transform;
+        use crate::settings::testing::settings;
+
+        use super::*;
+
+        const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+        fn update_event(value: i32) -> UpdateEvent {
+            serde_json::from_value(json!({
+                "ns": { "db": "db", "coll": "coll" },
+                "documentKey": { "_id": "doc" },
+                "updateDescription": { "updatedFields": { "val.first": value } },
+            }))
+            .unwrap()
+        }
+
+        #[tokio::test]
+        async fn settings_reload() {
+            let mut first_server = Server::new_async().await;
+            let first_mock = first_server
+                .mock("POST", "/api/publish")
+                .match_body(r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":1}}}"#)
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let mut second_server = Server::new_async().await;
+            let second_mock = second_server
+                .mock("POST", "/api/publish")
+                .match_body(r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":2}}}"#)
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let first_settings = settings(&["--centrifugo-url", &first_server.url()]);
+            let (settings_tx, settings_rx) = watch::channel(Arc::new(first_settings));
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !first_mock.matched_async().await {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+            let second_settings = settings(&["--centrifugo-url", &second_server.url()]);
+            settings_tx.send_replace(Arc::new(second_settings));
+            tx.send(update_event(2)).await.unwrap();
+            drop(tx);
+            task.await.unwrap();
+
+            first_mock.assert_async().await;
+            second_mock.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn resume_token() {
+            let mut server = Server::new_async().await;
+            let _mock = server
+                .mock("POST", "/api/publish")
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let deliveries = Deliveries::new(None);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                deliveries.clone(),
+            );
+            let mut event =
+                mongodb::bson::deserialize_from_document::<UpdateEvent>(mongodb::bson::doc! {
+                    "_id": { "_data": "8263C1192A000000012B0229296E04" },
+                    "ns": { "db": "db", "coll": "coll" },
+                    "documentKey": { "_id": "doc" },
+                    "updateDescription": { "updatedFields": { "val.first": 1 } },
+                })
+                .unwrap();
+            let expected = event.resume_token().cloned();
+
+            event.track(&deliveries);
+            tx.send(event).await.unwrap();
+            tx.send(update_event(2)).await.unwrap();
+            drop(tx);
+            let drained = task.await.unwrap();
+
+            assert!(expected.is_some());
+            assert_eq!(drained.resume_token, expected);
+            assert_eq!(drained.undelivered, 0);
+        }
+
+        #[tokio::test]
+        async fn failed_publication() {
+            let mut server = Server::new_async().await;
+            let _failed_mock = server
+                .mock("POST", "/api/publish")
+                .match_body(mockito::Matcher::Regex(r#""first":2"#.to_string()))
+                .with_body(r#"{"error":{"code":100,"message":"internal error"}}"#)
+                .create_async()
+                .await;
+            let _mock = server
+                .mock("POST", "/api/publish")
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let deliveries = Deliveries::new(None);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                3,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                deliveries.clone(),
+            );
+            let events = (1..=3)
+                .map(|value| {
+                    mongodb::bson::deserialize_from_document::<UpdateEvent>(mongodb::bson::doc! {
+                        "_id": { "_data": value.to_string() },
+                        "ns": { "db": "db", "coll": "coll" },
+                        "documentKey": { "_id": "doc" },
+                        "updateDescription": { "updatedFields": { "val.first": value } },
+                    })
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let expected = events[0].resume_token().cloned();
+
+            for mut event in events {
+                event.track(&deliveries);
+                tx.send(event).await.unwrap();
+            }
+            drop(tx);
+            let drained = task.await.unwrap();
+
+            assert_eq!(drained.resume_token, expected);
+            assert_eq!(drained.undelivered, 1);
+        }
+
+        #[tokio::test]
+        async fn dropped_change() {
+            let mut server = Server::new_async().await;
+            let _mock = server
+                .mock("POST", "/api/publish")
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let deliveries = Deliveries::new(None);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                deliveries.clone(),
+            );
+            let mut events = (1..=2).map(|value| {
+                let mut event =
+                    mongodb::bson::deserialize_from_document::<UpdateEvent>(mongodb::bson::doc! {
+                        "_id": { "_data": value.to_string() },
+                        "ns": { "db": "db", "coll": "coll" },
+                        "documentKey": { "_id": "doc" },
+                        "updateDescription": { "updatedFields": { "val.first": value } },
+                    })
+                    .unwrap();
+                event.track(&deliveries);
+                event
+            });
+
+            // As done by the change stream when the channel is full.
+            let ticket = events.next().unwrap().take_ticket();
+            deliveries.fail(ticket.as_slice());
+            tx.send(events.next().unwrap()).await.unwrap();
+            drop(tx);
+            let drained = task.await.unwrap();
+
+            assert_eq!(drained.resume_token, None);
+            assert_eq!(drained.undelivered, 1);
+        }
+
+        #[tokio::test]
+        async fn drain_timeout() {
+            let mut server = Server::new_async().await;
+            let _mock = server
+                .mock("POST", "/api/publish")
+                .with_body_from_request(|_| {
+                    std::thread::sleep(Duration::from_millis(300));
+                    br#"{"result":{}}"#.to_vec()
+                })
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                3,
+                None,
+                Default::default(),
+                Duration::from_millis(100),
+                Deliveries::new(None),
+            );
+
+            for value in 1..=3 {
+                tx.send(update_event(value)).await.unwrap();
+            }
+            drop(tx);
+            let drained = task.await.unwrap();
+
+            assert_eq!(drained.undelivered, 3);
+        }
+
+        #[tokio::test]
+        async fn inactive_channel() {
+            let mut server = Server::new_async().await;
+            let mock = server
+                .mock("POST", "/api/publish")
+                .with_body(r#"{"result":{}}"#)
+                .expect(0)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let active_channels = ActiveChannels::known(&["db.coll:other"]);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                active_channels.clone(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            drop(tx);
+            task.await.unwrap();
+
+            mock.assert_async().await;
+            assert_eq!(active_channels.skipped(), 1);
+        }
+
+        #[tokio::test]
+        async fn transformed_channel() {
+            let mut server = Server::new_async().await;
+            let mock = server
+                .mock("POST", "/api/publish")
+                .match_body(r#"{"channel":"db.coll:doc-a","data":1}"#)
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let mut settings = settings(&["--centrifugo-url", &server.url()]);
+            let script = r#"
+                fn publish(channel, data) {
+                    [
+                        #{ channel: channel + "-a", data: data.val.first },
+                        #{ channel: channel + "-b", data: data.val.first },
+                    ]
+                }
+            "#;
+            settings.transform = Some(Arc::new(Transform::compile(script).unwrap()));
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let active_channels = ActiveChannels::known(&["db.coll:doc-a"]);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                active_channels.clone(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            drop(tx);
+            task.await.unwrap();
+
+            mock.assert_async().await;
+            assert_eq!(active_channels.skipped(), 1);
+        }
+
+        #[tokio::test]
+        async fn deadband_of_inactive_channel() {
+            let mut server = Server::new_async().await;
+            let mock = server
+                .mock("POST", "/api/publish")
+                .match_body(r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":1}}}"#)
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&[
+                "--centrifugo-url",
+                &server.url(),
+                "--filter-deadband",
+                "first=5",
+            ]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let active_channels = ActiveChannels::known(&[]);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                None,
+                active_channels.clone(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            active_channels.insert("db.coll:doc");
+            tx.send(update_event(1)).await.unwrap();
+            drop(tx);
+            task.await.unwrap();
+
+            mock.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn versions() {
+            let mut server = Server::new_async().await;
+            let first_mock = server
+                .mock("POST", "/api/publish")
+                .match_body(
+                    r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":1},"version":1}}"#,
+                )
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let second_mock = server
+                .mock("POST", "/api/publish")
+                .match_body(
+                    r#"{"channel":"db.coll:doc","data":{"ts":{},"val":{"first":2},"version":2}}"#,
+                )
+                .with_body(r#"{"result":{}}"#)
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let versions = Versions::default();
+            let active_channels = ActiveChannels::known(&["db.coll:doc"]);
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                1,
+                Some(versions.clone()),
+                active_channels.clone(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            tx.send(update_event(2)).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !second_mock.matched_async().await {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(versions.current("db.coll:doc"), 2);
+
+            active_channels.refresh(&[]);
+            tx.send(update_event(3)).await.unwrap();
+            drop(tx);
+            task.await.unwrap();
+
+            first_mock.assert_async().await;
+            assert_eq!(versions.current("db.coll:doc"), 0);
+        }
+
+        #[tokio::test]
+        async fn multiple_clusters() {
+            let mut slow_server = Server::new_async().await;
+            let slow_mock = slow_server
+                .mock("POST", "/api/publish")
+                .match_header("X-API-Key", "somekey")
+                .with_body_from_request(|_| {
+                    std::thread::sleep(Duration::from_millis(300));
+                    br#"{"result":{}}"#.to_vec()
+                })
+                .expect(2)
+                .create_async()
+                .await;
+            let mut fast_server = Server::new_async().await;
+            let fast_mock = fast_server
+                .mock("POST", "/api/publish")
+                .match_header("X-API-Key", "otherkey")
+                .with_body(r#"{"result":{}}"#)
+                .expect(2)
+                .create_async()
+                .await;
+            let cluster = format!("name=fast,url={},api_key=otherkey", fast_server.url());
+            let settings = settings(&[
+                "--centrifugo-url",
+                &slow_server.url(),
+                "--centrifugo-cluster",
+                &cluster,
+            ]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, _, task) = handle_tags_update(
+                settings_rx,
+                2,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            tx.send(update_event(1)).await.unwrap();
+            tx.send(update_event(2)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            fast_mock.assert_async().await;
+            assert!(!slow_mock.matched_async().await);
+            drop(tx);
+            task.await.unwrap();
+            slow_mock.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn queue_levels() {
+            let mut server = Server::new_async().await;
+            let _mock = server
+                .mock("POST", "/api/publish")
+                .with_body_from_request(|_| {
+                    std::thread::sleep(Duration::from_millis(300));
+                    br#"{"result":{}}"#.to_vec()
+                })
+                .create_async()
+                .await;
+            let settings = settings(&["--centrifugo-url", &server.url()]);
+            let (_settings_tx, settings_rx) = watch::channel(Arc::new(settings));
+            let (tx, queues_tx, task) = handle_tags_update(
+                settings_rx,
+                3,
+                None,
+                Default::default(),
+                DRAIN_TIMEOUT,
+                Deliveries::new(None),
+            );
+
+            for value in 1..=3 {
+                tx.send(update_event(value)).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let levels = queues_tx.roundtrip(()).await.unwrap();
+            assert_eq!(
+                levels.tags_update,
+                QueueLevel {
+                    length: 0,
+                    capacity: 3
+                }
+            );
+            assert_eq!(levels.clusters.len(), 1);
+            assert_eq!(levels.clusters[0].0.as_ref(), DEFAULT_CLUSTER);
+            assert_eq!(
+                levels.clusters[0].1,
+                QueueLevel {
+                    length: 2,
+                    capacity: 3
+                }
+            );
+            drop(tx);
+            task.await.unwrap();
+        }
+    }
+
+    mod cluster_spec {
+        use super::*;
+
+        #[test]
+        fn api_key() {
+            let spec = "name=eu,url=https://eu.example.com,api_key=somekey"
+                .parse::<ClusterSpec>()
+                .unwrap();
+            assert_eq!(spec.name, "eu");
+            assert_eq!(spec.urls.len(), 1);
+            assert_eq!(spec.urls[0].as_str(), "https://eu.example.com/");
+            assert_eq!(spec.api_key.as_deref(), Some("somekey"));
+            assert!(spec.api_key_file.is_none());
+        }
+
+        #[test]
+        fn multiple_urls() {
+            let spec = "name=eu,url=https://eu1.example.com,url=https://eu2.example.com,api_key=k"
+                .parse::<ClusterSpec>()
+                .unwrap();
+            let urls = spec.urls.iter().map(Url::as_str).collect::<Vec<_>>();
+            assert_eq!(
+                urls,
+                ["https://eu1.example.com/", "https://eu2.example.com/"]
+            );
+        }
+
+        #[test]
+        fn api_key_file() {
+            let spec = "name=eu,url=https://eu.example.com,api_key_file=/run/secrets/eu"
+                .parse::<ClusterSpec>()
+                .unwrap();
+            assert!(spec.api_key.is_none());
+            assert_eq!(spec.api_key_file, Some(PathBuf::from("/run/secrets/eu")));
+        }
+
+        #[test]
+        fn invalid() {
+            for s in [
+                "url=https://eu.example.com,api_key=somekey",
+                "name=eu,api_key=somekey",
+                "name=eu,url=https://eu.example.com",
+                "name=eu,url=https://eu.example.com,api_key=a,api_key_file=b",
+                "name=eu,url=not an url,api_key=somekey",
+                "name=eu,url=https://eu.example.com,api_key=a,other=b",
+                "name=eu,url=https://eu.example.com,url=not an url,api_key=a",
+                "name",
+            ] {
+                assert!(s.parse::<ClusterSpec>().is_err(), "{s}");
+            }
         }
     }
 }
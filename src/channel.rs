@@ -9,24 +9,37 @@ const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
 
 pub(crate) struct RoundtripSender<S, R> {
     inner: mpsc::Sender<RequestPayload<S, R>>,
+    send_timeout: Duration,
+    receive_timeout: Duration,
 }
 
 impl<S, R> Clone for RoundtripSender<S, R> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            send_timeout: self.send_timeout,
+            receive_timeout: self.receive_timeout,
         }
     }
 }
 
 impl<S, R> RoundtripSender<S, R> {
+    /// Sets the maximum durations for sending a request, and for receiving its reply.
+    pub(crate) fn with_timeouts(self, send_timeout: Duration, receive_timeout: Duration) -> Self {
+        Self {
+            send_timeout,
+            receive_timeout,
+            ..self
+        }
+    }
+
     pub(crate) async fn roundtrip(&self, request: S) -> Result<R, String> {
         let (reply_tx, reply_rx) = oneshot::channel();
         self.inner
-            .send_timeout((request, reply_tx), SEND_TIMEOUT)
+            .send_timeout((request, reply_tx), self.send_timeout)
             .await
             .map_err(|err| format!("request sending: {err}"))?;
-        let reply = tokio::time::timeout(RECEIVE_TIMEOUT, reply_rx)
+        let reply = tokio::time::timeout(self.receive_timeout, reply_rx)
             .await
             .map_err(|err| format!("reply receiving: {err}"))?
             .map_err(|err| format!("reply receiving: {err}"))?;
@@ -38,7 +51,11 @@ pub(crate) fn roundtrip_channel<S, R>(
     buffer: usize,
 ) -> (RoundtripSender<S, R>, mpsc::Receiver<RequestPayload<S, R>>) {
     let (inner, rx) = mpsc::channel(buffer);
-    let sender = RoundtripSender { inner };
+    let sender = RoundtripSender {
+        inner,
+        send_timeout: SEND_TIMEOUT,
+        receive_timeout: RECEIVE_TIMEOUT,
+    };
     (sender, rx)
 }
 
@@ -86,6 +103,19 @@ mod tests {
             assert!(result.is_err());
         }
 
+        #[tokio::test(start_paused = true)]
+        async fn custom_reply_timeout() {
+            let (tx, mut rx) = roundtrip_channel::<(), ()>(1);
+            let tx = tx.with_timeouts(SEND_TIMEOUT, RECEIVE_TIMEOUT * 4);
+            tokio::spawn(async move {
+                let (_, reply_tx) = rx.recv().await.unwrap();
+                tokio::time::sleep(RECEIVE_TIMEOUT * 2).await;
+                reply_tx.send(()).unwrap();
+            });
+            let result = tx.roundtrip(()).await;
+            assert!(result.is_ok());
+        }
+
         #[tokio::test]
         async fn success() {
             let (tx, mut rx) = roundtrip_channel::<u8, u8>(1);
@@ -0,0 +1,315 @@
+use std::error::Error as _;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::error::{ContextKind, ContextValue, ErrorKind};
+use clap::{Arg, ArgAction, Command, CommandFactory, FromArgMatches};
+use toml::{Table, Value};
+
+/// Identifier of the configuration file argument.
+const CONFIG_ARG_ID: &str = "config";
+
+/// Parses command line arguments, taking values from the configuration file (if any) as defaults.
+///
+/// Values are taken, in order of precedence, from:
+///
+/// 1. command line arguments;
+/// 2. environment variables;
+/// 3. configuration file;
+/// 4. built-in defaults.
+pub(crate) fn parse_args_from<T, I>(args: I) -> Result<T, clap::Error>
+where
+    T: CommandFactory + FromArgMatches,
+    I: IntoIterator,
+    I::Item: Into<OsString>,
+{
+    let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+    let mut command = T::command();
+
+    let preliminary = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)?;
+    if let Ok(Some(path)) = preliminary.try_get_one::<PathBuf>(CONFIG_ARG_ID) {
+        command = apply_config_file(command, path)?;
+    }
+
+    let matches = command.try_get_matches_from(args)?;
+    T::from_arg_matches(&matches)
+}
+
+/// Sets values from the configuration file as defaults of corresponding arguments.
+///
+/// Keys of the file are argument names, with underscores instead of dashes. Keys of tables are
+/// prefixed by the table name, so that `url` in `[centrifugo]` table is the same as
+/// `centrifugo_url` top-level key.
+fn apply_config_file(mut command: Command, path: &Path) -> Result<Command, clap::Error> {
+    let file_error = |command: &mut Command, kind, message: String| {
+        command.error(
+            kind,
+            format!("configuration file {}: {message}", path.display()),
+        )
+    };
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| file_error(&mut command, ErrorKind::Io, err.to_string()))?;
+    let table = content
+        .parse::<Table>()
+        .map_err(|err| file_error(&mut command, ErrorKind::InvalidValue, err.to_string()))?;
+    let mut entries = Vec::new();
+    flatten_table(&table, "", &mut entries)
+        .map_err(|err| file_error(&mut command, ErrorKind::InvalidValue, err))?;
+
+    for (key, value) in entries {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some())
+            .filter(|arg| arg.get_id() != CONFIG_ARG_ID)
+        else {
+            let message = format!("unknown key `{key}`");
+            return Err(file_error(
+                &mut command,
+                ErrorKind::UnknownArgument,
+                message,
+            ));
+        };
+        let values = default_values(arg, &value)
+            .and_then(|values| validate(arg, &values).map(|_| values))
+            .map_err(|err| format!("key `{key}`: {err}"))
+            .map_err(|err| file_error(&mut command, ErrorKind::InvalidValue, err))?;
+        command = command.mut_arg(&key, |arg| arg.default_values(values).required(false));
+    }
+
+    Ok(command)
+}
+
+fn flatten_table(
+    table: &Table,
+    prefix: &str,
+    entries: &mut Vec<(String, Value)>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let key = format!("{prefix}{}", key.replace('-', "_"));
+        match value {
+            Value::Table(table) => flatten_table(table, &format!("{key}_"), entries)?,
+            value => entries.push((key, value.clone())),
+        }
+    }
+    Ok(())
+}
+
+fn scalar_to_string(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        other => Err(format!("unsupported {} value", other.type_str())),
+    }
+}
+
+/// Converts a configuration file value to default values of the argument.
+fn default_values(arg: &Arg, value: &Value) -> Result<Vec<String>, String> {
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue | ArgAction::SetFalse, Value::Boolean(b)) => Ok(vec![b.to_string()]),
+        (ArgAction::SetTrue | ArgAction::SetFalse, _) => Err("expected a boolean".to_string()),
+        (ArgAction::Count, Value::Integer(i)) => Ok(vec![i.to_string()]),
+        (ArgAction::Count, _) => Err("expected an integer".to_string()),
+        (ArgAction::Append, Value::Array(values)) => values.iter().map(scalar_to_string).collect(),
+        (ArgAction::Set | ArgAction::Append, value) => Ok(vec![scalar_to_string(value)?]),
+        _ => Err("not configurable in file".to_string()),
+    }
+}
+
+/// Validates default values with the value parser of the argument.
+fn validate(arg: &Arg, values: &[String]) -> Result<(), String> {
+    let mut probe = Arg::new(arg.get_id().clone())
+        .long("probe")
+        .action(arg.get_action().clone())
+        .value_parser(arg.get_value_parser().clone())
+        .default_values(values);
+    if let Some(num_args) = arg.get_num_args() {
+        probe = probe.num_args(num_args);
+    }
+    Command::new("probe")
+        .no_binary_name(true)
+        .arg(probe)
+        .try_get_matches_from(Vec::<OsString>::new())
+        .map(drop)
+        .map_err(|err| {
+            let mut message = match err.get(ContextKind::InvalidValue) {
+                Some(ContextValue::String(value)) => format!("invalid value `{value}`"),
+                _ => "invalid value".to_string(),
+            };
+            if let Some(source) = err.source() {
+                message += &format!(": {source}");
+            }
+            if let Some(ContextValue::Strings(valid)) = err.get(ContextKind::ValidValue) {
+                message += &format!(" (possible values: {})", valid.join(", "));
+            }
+            message
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write as _;
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+    enum Mode {
+        First,
+        Second,
+    }
+
+    #[derive(Debug, Parser)]
+    struct Args {
+        #[arg(env = "CONFIG_TESTS_CONFIG", long)]
+        config: Option<PathBuf>,
+
+        #[arg(env = "CONFIG_TESTS_NAME", long)]
+        name: String,
+
+        #[arg(long, default_value = "10")]
+        section_size: u8,
+
+        #[arg(long, value_parser = humantime::parse_duration)]
+        section_interval: Option<Duration>,
+
+        #[arg(long, value_enum, default_value = "first")]
+        mode: Mode,
+
+        #[arg(long)]
+        flag: bool,
+
+        #[arg(long, value_delimiter = ',')]
+        items: Vec<String>,
+    }
+
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, content: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "centrifugo-change-stream-config-tests-{}-{name}.toml",
+                std::process::id()
+            ));
+            let mut file = std::fs::File::create(&path).unwrap();
+            file.write_all(content.as_bytes()).unwrap();
+            Self(path)
+        }
+
+        fn parse(&self, extra_args: &[&str]) -> Result<Args, clap::Error> {
+            let args = ["test", "--config", self.0.to_str().unwrap()]
+                .into_iter()
+                .chain(extra_args.iter().copied());
+            parse_args_from(args)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn without_file() {
+        let args = parse_args_from::<Args, _>(["test", "--name", "cli"]).unwrap();
+        assert_eq!(args.name, "cli");
+        assert_eq!(args.section_size, 10);
+        assert!(args.config.is_none());
+    }
+
+    #[test]
+    fn missing_file() {
+        let result = parse_args_from::<Args, _>(["test", "--config", "/nonexistent.toml"]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn invalid_toml() {
+        let file = ConfigFile::new("invalid_toml", "name = ");
+        let err = file.parse(&[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+    }
+
+    #[test]
+    fn values_from_file() {
+        let file = ConfigFile::new(
+            "values_from_file",
+            r#"
+                name = "file"
+                mode = "second"
+                flag = true
+                items = ["a", "b"]
+
+                [section]
+                size = 42
+                interval = "2s"
+            "#,
+        );
+        let args = file.parse(&[]).unwrap();
+        assert_eq!(args.name, "file");
+        assert_eq!(args.section_size, 42);
+        assert_eq!(args.section_interval, Some(Duration::from_secs(2)));
+        assert_eq!(args.mode, Mode::Second);
+        assert!(args.flag);
+        assert_eq!(args.items, ["a", "b"]);
+    }
+
+    #[test]
+    fn command_line_precedence() {
+        let file = ConfigFile::new(
+            "command_line_precedence",
+            "name = \"file\"\nitems = [\"a\"]",
+        );
+        let args = file.parse(&["--name", "cli", "--items", "b,c"]).unwrap();
+        assert_eq!(args.name, "cli");
+        assert_eq!(args.items, ["b", "c"]);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let file = ConfigFile::new("unknown_key", "name = \"file\"\nunknown = 1");
+        let err = file.parse(&[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+        assert!(err.to_string().contains("unknown key `unknown`"));
+    }
+
+    #[test]
+    fn config_key() {
+        let file = ConfigFile::new("config_key", "name = \"file\"\nconfig = \"other.toml\"");
+        let err = file.parse(&[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn invalid_value() {
+        let file = ConfigFile::new("invalid_value", "name = \"file\"\nsection_size = 1000");
+        let err = file.parse(&[]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+        assert!(
+            err.to_string()
+                .contains("key `section_size`: invalid value `1000`")
+        );
+    }
+
+    #[test]
+    fn invalid_enum_value() {
+        let file = ConfigFile::new("invalid_enum_value", "name = \"file\"\nmode = \"third\"");
+        let err = file.parse(&[]).unwrap_err();
+        assert!(err.to_string().contains("possible values: first, second"));
+    }
+
+    #[test]
+    fn invalid_type() {
+        let file = ConfigFile::new("invalid_type", "name = \"file\"\nflag = \"yes\"");
+        let err = file.parse(&[]).unwrap_err();
+        assert!(err.to_string().contains("key `flag`: expected a boolean"));
+    }
+}
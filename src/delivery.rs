@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mongodb::change_stream::event::ResumeToken;
+
+struct Pending {
+    resume_token: ResumeToken,
+    delivered: bool,
+}
+
+struct Tracking {
+    next_sequence: u64,
+    /// Changes not delivered yet, or following one not delivered yet, by sequence number.
+    pending: BTreeMap<u64, Pending>,
+    /// Sequence number of the first change which failed to be delivered.
+    first_failure: Option<u64>,
+    /// Number of publications which failed to be delivered.
+    failures: usize,
+    /// Resume token of the last change which, as all previous ones, has been delivered.
+    delivered: Option<ResumeToken>,
+}
+
+impl Tracking {
+    fn complete(&mut self, sequence: u64) {
+        if let Some(pending) = self.pending.get_mut(&sequence) {
+            pending.delivered = true;
+        }
+        while let Some(first) = self.pending.first_entry()
+            && first.get().delivered
+        {
+            self.delivered = Some(first.remove().resume_token);
+        }
+    }
+
+    fn fail(&mut self, sequence: u64) {
+        if self.first_failure.is_none_or(|first| sequence < first) {
+            self.first_failure = Some(sequence);
+            // Later changes can not be delivered before this one anymore.
+            self.pending.split_off(&sequence);
+        }
+    }
+}
+
+/// Delivery tracking of changes received from the change stream, giving the resume token up to
+/// which all changes have been delivered to Centrifugo.
+#[derive(Clone)]
+pub(crate) struct Deliveries(Arc<Mutex<Tracking>>);
+
+impl Deliveries {
+    /// Creates a tracking, starting after the change of the given resume token, if any.
+    pub(crate) fn new(start_after: Option<ResumeToken>) -> Self {
+        Self(Arc::new(Mutex::new(Tracking {
+            next_sequence: 0,
+            pending: BTreeMap::new(),
+            first_failure: None,
+            failures: 0,
+            delivered: start_after,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tracking> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Starts tracking the delivery of a change, in order of reception.
+    pub(crate) fn track(&self, resume_token: ResumeToken) -> Ticket {
+        let mut tracking = self.lock();
+        let sequence = tracking.next_sequence;
+        tracking.next_sequence += 1;
+        if tracking.first_failure.is_none() {
+            tracking.pending.insert(
+                sequence,
+                Pending {
+                    resume_token,
+                    delivered: false,
+                },
+            );
+        }
+        Ticket(Arc::new(TicketInner {
+            sequence,
+            deliveries: self.clone(),
+        }))
+    }
+
+    /// Records the failed delivery of a publication, carrying the changes of given tickets.
+    pub(crate) fn fail(&self, tickets: &[Ticket]) {
+        let mut tracking = self.lock();
+        tracking.failures += 1;
+        for ticket in tickets {
+            tracking.fail(ticket.0.sequence);
+        }
+    }
+
+    /// Returns the resume token of the last change which, as all previous ones, has been
+    /// delivered.
+    pub(crate) fn delivered(&self) -> Option<ResumeToken> {
+        self.lock().delivered.clone()
+    }
+
+    /// Returns the number of publications which failed to be delivered.
+    pub(crate) fn failures(&self) -> usize {
+        self.lock().failures
+    }
+}
+
+struct TicketInner {
+    sequence: u64,
+    deliveries: Deliveries,
+}
+
+impl Drop for TicketInner {
+    fn drop(&mut self) {
+        self.deliveries.lock().complete(self.sequence);
+    }
+}
+
+/// Handle on the delivery of a change, carried along its publications: the change is delivered
+/// once all handles are dropped, unless the delivery of a publication failed.
+#[derive(Clone)]
+pub(crate) struct Ticket(Arc<TicketInner>);
+
+impl fmt::Debug for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Ticket").field(&self.0.sequence).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+
+    fn token(n: i32) -> ResumeToken {
+        mongodb::bson::deserialize_from_document(doc! { "_data": n.to_string() }).unwrap()
+    }
+
+    #[test]
+    fn start_after() {
+        let deliveries = Deliveries::new(Some(token(0)));
+        let _first = deliveries.track(token(1));
+        assert_eq!(deliveries.delivered(), Some(token(0)));
+    }
+
+    #[test]
+    fn in_order() {
+        let deliveries = Deliveries::new(None);
+        let first = deliveries.track(token(1));
+        let second = deliveries.track(token(2));
+        drop(first);
+        assert_eq!(deliveries.delivered(), Some(token(1)));
+        drop(second);
+        assert_eq!(deliveries.delivered(), Some(token(2)));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let deliveries = Deliveries::new(None);
+        let first = deliveries.track(token(1));
+        let second = deliveries.track(token(2));
+        drop(second);
+        assert_eq!(deliveries.delivered(), None);
+        drop(first);
+        assert_eq!(deliveries.delivered(), Some(token(2)));
+    }
+
+    #[test]
+    fn shared_ticket() {
+        let deliveries = Deliveries::new(None);
+        let ticket = deliveries.track(token(1));
+        let other = ticket.clone();
+        drop(ticket);
+        assert_eq!(deliveries.delivered(), None);
+        drop(other);
+        assert_eq!(deliveries.delivered(), Some(token(1)));
+    }
+
+    #[test]
+    fn failure() {
+        let deliveries = Deliveries::new(Some(token(0)));
+        let first = deliveries.track(token(1));
+        let second = deliveries.track(token(2));
+        let third = deliveries.track(token(3));
+        deliveries.fail(&[second.clone(), third.clone()]);
+        drop(third);
+        drop(second);
+        assert_eq!(deliveries.delivered(), Some(token(0)));
+        drop(first);
+        assert_eq!(deliveries.delivered(), Some(token(1)));
+        drop(deliveries.track(token(4)));
+        assert_eq!(deliveries.delivered(), Some(token(1)));
+        assert_eq!(deliveries.failures(), 1);
+    }
+
+    #[test]
+    fn untracked_failure() {
+        let deliveries = Deliveries::new(None);
+        let first = deliveries.track(token(1));
+        deliveries.fail(&[]);
+        drop(first);
+        assert_eq!(deliveries.delivered(), Some(token(1)));
+        assert_eq!(deliveries.failures(), 1);
+    }
+}
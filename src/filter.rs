@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use clap::Args;
+use mongodb::bson::Bson;
+
+#[derive(Args, Clone)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Only publish these fields (comma-separated)
+    #[arg(env, long, value_delimiter = ',')]
+    filter_include_fields: Vec<String>,
+
+    /// Never publish these fields (comma-separated)
+    #[arg(env, long, value_delimiter = ',')]
+    filter_exclude_fields: Vec<String>,
+
+    /// Only publish a field when its value changes by more than a deadband (comma-separated FIELD=DEADBAND)
+    #[arg(env, long, value_delimiter = ',')]
+    filter_deadband: Vec<Deadband>,
+}
+
+#[derive(Clone, Debug)]
+struct Deadband {
+    field: String,
+    value: f64,
+}
+
+impl FromStr for Deadband {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, value) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("missing `=` in `{s}`"))?;
+        if field.is_empty() {
+            return Err(format!("missing field name in `{s}`"));
+        }
+        let value = value
+            .parse::<f64>()
+            .map_err(|err| format!("invalid deadband value in `{s}`: {err}"))?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!(
+                "deadband value must be a non-negative number in `{s}`"
+            ));
+        }
+        Ok(Self {
+            field: field.to_string(),
+            value,
+        })
+    }
+}
+
+/// Filtering rules for published fields.
+#[derive(Default)]
+pub(crate) struct Filter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+    deadbands: HashMap<String, f64>,
+    /// Last published value of deadband fields, by channel.
+    last_published: HashMap<String, HashMap<String, f64>>,
+    /// Retained value of deadband fields not published yet, by channel.
+    pending: HashMap<String, HashMap<String, f64>>,
+}
+
+impl Filter {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            include: config.filter_include_fields.clone(),
+            exclude: config.filter_exclude_fields.clone(),
+            deadbands: config
+                .filter_deadband
+                .iter()
+                .map(|deadband| (deadband.field.clone(), deadband.value))
+                .collect(),
+            last_published: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Replaces the rules, keeping last published and pending values.
+    pub(crate) fn reload(&mut self, config: &Config) {
+        let last_published = std::mem::take(&mut self.last_published);
+        let pending = std::mem::take(&mut self.pending);
+        *self = Self {
+            last_published,
+            pending,
+            ..Self::new(config)
+        };
+    }
+
+    /// Records the retained values of the channel as published, deadbands applying from them.
+    pub(crate) fn published(&mut self, channel: &str) {
+        if let Some(pending) = self.pending.remove(channel) {
+            self.last_published
+                .entry(channel.to_string())
+                .or_default()
+                .extend(pending);
+        }
+    }
+
+    /// Discards the retained values of the channel, which were not published.
+    pub(crate) fn unpublished(&mut self, channel: &str) {
+        self.pending.remove(channel);
+    }
+
+    /// Retains last published and pending values of the channels matching the predicate.
+    pub(crate) fn retain_channels(&mut self, mut f: impl FnMut(&str) -> bool) {
+        self.last_published.retain(|channel, _| f(channel));
+        self.pending.retain(|channel, _| f(channel));
+    }
+
+    /// Returns whether the field is allowed by include and exclude lists.
+    pub(crate) fn is_allowed(&self, field: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|f| f == field))
+            && !self.exclude.iter().any(|f| f == field)
+    }
+
+    /// Retains the updated fields (`val.<field>` and `ts.<field>` keys) which should be published
+    /// for the channel.
+    ///
+    /// Timestamps of values suppressed by a deadband are suppressed too. Retained values of
+    /// deadband fields only become the reference of later changes once [`Self::published`].
+    pub(crate) fn retain_updated_fields(
+        &mut self,
+        channel: &str,
+        updated_fields: &mut HashMap<String, Bson>,
+    ) {
+        let mut suppressed = Vec::new();
+        updated_fields.retain(|key, value| {
+            let Some(field) = key.strip_prefix("val.") else {
+                return true;
+            };
+            if !self.is_allowed(field) {
+                return false;
+            }
+            let (Some(&deadband), Some(current)) = (self.deadbands.get(field), as_f64(value))
+            else {
+                return true;
+            };
+            let last_published = self
+                .last_published
+                .get(channel)
+                .and_then(|last_published| last_published.get(field));
+            match last_published {
+                Some(last) if (current - last).abs() <= deadband => {
+                    suppressed.push(field.to_string());
+                    false
+                }
+                _ => {
+                    self.pending
+                        .entry(channel.to_string())
+                        .or_default()
+                        .insert(field.to_string(), current);
+                    true
+                }
+            }
+        });
+        updated_fields.retain(|key, _| {
+            let Some(field) = key.strip_prefix("ts.") else {
+                return true;
+            };
+            self.is_allowed(field) && !suppressed.iter().any(|f| f == field)
+        });
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Double(v) => Some(*v),
+        Bson::Int32(v) => Some((*v).into()),
+        Bson::Int64(v) => Some(*v as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::DateTime;
+
+    use super::*;
+
+    mod deadband {
+        use super::*;
+
+        #[test]
+        fn missing_separator() {
+            assert!("field".parse::<Deadband>().is_err());
+        }
+
+        #[test]
+        fn missing_field() {
+            assert!("=1.5".parse::<Deadband>().is_err());
+        }
+
+        #[test]
+        fn invalid_value() {
+            assert!("field=abc".parse::<Deadband>().is_err());
+            assert!("field=-1".parse::<Deadband>().is_err());
+        }
+
+        #[test]
+        fn success() {
+            let deadband = "some=field=0.5".parse::<Deadband>().unwrap();
+            assert_eq!(deadband.field, "some=field");
+            assert_eq!(deadband.value, 0.5);
+        }
+    }
+
+    mod filter {
+        use super::*;
+
+        fn updated_fields(entries: &[(&str, Bson)]) -> HashMap<String, Bson> {
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect()
+        }
+
+        #[test]
+        fn include_and_exclude() {
+            let mut filter = Filter {
+                include: vec!["first".to_string(), "second".to_string()],
+                exclude: vec!["second".to_string()],
+                ..Default::default()
+            };
+            let mut fields = updated_fields(&[
+                ("val.first", Bson::Int32(1)),
+                ("val.second", Bson::Int32(2)),
+                ("val.third", Bson::Int32(3)),
+                ("ts.first", Bson::DateTime(DateTime::from_millis(0))),
+                ("ts.third", Bson::DateTime(DateTime::from_millis(0))),
+                ("other", Bson::Null),
+            ]);
+            filter.retain_updated_fields("chan", &mut fields);
+            let mut keys = fields.keys().map(String::as_str).collect::<Vec<_>>();
+            keys.sort_unstable();
+            assert_eq!(keys, ["other", "ts.first", "val.first"]);
+        }
+
+        #[test]
+        fn deadband() {
+            let mut filter = Filter {
+                deadbands: HashMap::from([("first".to_string(), 1.0)]),
+                ..Default::default()
+            };
+            let steps = [
+                (10.0, true),
+                (10.5, false),
+                (11.0, false),
+                (11.5, true),
+                (10.0, true),
+            ];
+            for (value, published) in steps {
+                let mut fields = updated_fields(&[
+                    ("val.first", Bson::Double(value)),
+                    ("ts.first", Bson::DateTime(DateTime::from_millis(0))),
+                    ("val.second", Bson::Double(value)),
+                ]);
+                filter.retain_updated_fields("chan", &mut fields);
+                filter.published("chan");
+                assert_eq!(fields.contains_key("val.first"), published, "{value}");
+                assert_eq!(fields.contains_key("ts.first"), published, "{value}");
+                assert!(fields.contains_key("val.second"));
+            }
+        }
+
+        #[test]
+        fn deadband_unpublished() {
+            let mut filter = Filter {
+                deadbands: HashMap::from([("first".to_string(), 1.0)]),
+                ..Default::default()
+            };
+            let steps = [(10.0, true, true), (12.0, true, false), (10.5, false, true)];
+            for (value, retained, published) in steps {
+                let mut fields = updated_fields(&[("val.first", Bson::Double(value))]);
+                filter.retain_updated_fields("chan", &mut fields);
+                assert_eq!(fields.contains_key("val.first"), retained, "{value}");
+                if published {
+                    filter.published("chan");
+                } else {
+                    filter.unpublished("chan");
+                }
+            }
+        }
+
+        #[test]
+        fn deadband_per_channel() {
+            let mut filter = Filter {
+                deadbands: HashMap::from([("first".to_string(), 1.0)]),
+                ..Default::default()
+            };
+            let mut fields = updated_fields(&[("val.first", Bson::Int32(10))]);
+            filter.retain_updated_fields("chan1", &mut fields);
+            filter.published("chan1");
+            assert!(fields.contains_key("val.first"));
+            let mut fields = updated_fields(&[("val.first", Bson::Int32(10))]);
+            filter.retain_updated_fields("chan2", &mut fields);
+            assert!(fields.contains_key("val.first"));
+        }
+
+        #[test]
+        fn retain_channels() {
+            let mut filter = Filter {
+                deadbands: HashMap::from([("first".to_string(), 1.0)]),
+                ..Default::default()
+            };
+            for channel in ["chan1", "chan2"] {
+                let mut fields = updated_fields(&[("val.first", Bson::Int32(10))]);
+                filter.retain_updated_fields(channel, &mut fields);
+                filter.published(channel);
+            }
+            filter.retain_channels(|channel| channel == "chan1");
+            let mut fields = updated_fields(&[("val.first", Bson::Int32(10))]);
+            filter.retain_updated_fields("chan1", &mut fields);
+            assert!(!fields.contains_key("val.first"));
+            let mut fields = updated_fields(&[("val.first", Bson::Int32(10))]);
+            filter.retain_updated_fields("chan2", &mut fields);
+            assert!(fields.contains_key("val.first"));
+        }
+
+        #[test]
+        fn deadband_non_numeric() {
+            let mut filter = Filter {
+                deadbands: HashMap::from([("first".to_string(), 1.0)]),
+                ..Default::default()
+            };
+            for _ in 0..2 {
+                let mut fields = updated_fields(&[("val.first", Bson::Boolean(true))]);
+                filter.retain_updated_fields("chan", &mut fields);
+                assert!(fields.contains_key("val.first"));
+            }
+        }
+    }
+}
@@ -1,18 +1,121 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use axum::extract::State;
-use axum::http::StatusCode;
+use anyhow::Context as _;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderName, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::{Json, Router, routing};
+use clap::Args;
 use serde::Deserialize;
 use serde_json::{Value, json};
-use tracing::{debug, error, instrument};
+use tracing::{Instrument, debug, error, info, info_span, instrument};
 
-use crate::centrifugo::HealthChannel;
-use crate::db::CurrentDataChannel;
-use crate::model::EnsureObject;
+use crate::centrifugo::{self, ClusterHealth, QueueLevel, QueueLevels, QueuesChannel, Versions};
+use crate::db::{
+    self, ChangeStreamState, ChangeStreamStatus, ChangeStreamStatusReceiver, CurrentDataChannel,
+    MongoDBCollection, ReplayStart,
+};
+use crate::model::{EnsureObject, MongoDBData, PublicationMode};
+use crate::replay;
+use crate::script::Transform;
+use crate::secret::{Secret, constant_time_eq};
+use crate::settings::SettingsReceiver;
+use crate::subscriptions::ActiveChannels;
+use crate::tls::ConnectionInfo;
 
 type StatusWithText = (StatusCode, &'static str);
 
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Name of the header carrying the shared secret of Centrifugo proxy requests
+    #[arg(env, long, default_value = "X-Proxy-Token")]
+    proxy_auth_header: HeaderName,
+
+    /// Shared secret expected in Centrifugo proxy requests
+    #[arg(env, long, hide_env_values = true)]
+    proxy_auth_token: Option<String>,
+
+    /// File containing the shared secret expected in Centrifugo proxy requests (read again when
+    /// modified)
+    #[arg(env, long, conflicts_with = "proxy_auth_token")]
+    proxy_auth_token_file: Option<PathBuf>,
+
+    /// Bearer token expected in administration requests (administration endpoints are disabled
+    /// if not set)
+    #[arg(env, long, hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// File containing the bearer token expected in administration requests (read again when
+    /// modified)
+    #[arg(env, long, conflicts_with = "admin_token")]
+    admin_token_file: Option<PathBuf>,
+}
+
+/// Authentication of Centrifugo proxy requests by a shared secret header.
+pub(crate) struct ProxyAuth {
+    header: HeaderName,
+    token: Secret,
+}
+
+impl ProxyAuth {
+    /// Returns the proxy authentication, if a shared secret is configured.
+    pub(crate) fn new(config: &Config) -> anyhow::Result<Option<Self>> {
+        let token = match (&config.proxy_auth_token, &config.proxy_auth_token_file) {
+            (_, Some(path)) => Secret::from_file(path).with_context(|| {
+                format!(
+                    "error reading proxy authentication token file {}",
+                    path.display()
+                )
+            })?,
+            (Some(token), None) => Secret::from(token.as_str()),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self {
+            header: config.proxy_auth_header.clone(),
+            token,
+        }))
+    }
+}
+
+/// Administration endpoints, authenticated by a bearer token.
+pub(crate) struct Admin {
+    token: Secret,
+    collection: MongoDBCollection,
+    mongodb_config: db::Config,
+    publication_mode: PublicationMode,
+    /// Held while a replay is running, so that replays do not overlap.
+    replay_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Admin {
+    /// Returns the administration endpoints state, if a token is configured.
+    pub(crate) fn new(
+        config: &Config,
+        collection: MongoDBCollection,
+        mongodb_config: &db::Config,
+        publication_mode: PublicationMode,
+    ) -> anyhow::Result<Option<Self>> {
+        let token = match (&config.admin_token, &config.admin_token_file) {
+            (_, Some(path)) => Secret::from_file(path).with_context(|| {
+                format!("error reading administration token file {}", path.display())
+            })?,
+            (Some(token), None) => Secret::from(token.as_str()),
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self {
+            token,
+            collection,
+            mongodb_config: mongodb_config.clone(),
+            publication_mode,
+            replay_lock: Default::default(),
+        }))
+    }
+}
+
 const INTERNAL_ERROR: StatusWithText = (StatusCode::INTERNAL_SERVER_ERROR, "internal server error");
 
 #[derive(Debug, Deserialize)]
@@ -57,32 +160,330 @@ impl From<CentrifugoProxyError> for Json<Value> {
 #[derive(Clone)]
 pub(crate) struct AppState {
     pub(crate) namespace_prefix: Arc<str>,
-    pub(crate) health_channel: HealthChannel,
+    pub(crate) centrifugo_health_channel: centrifugo::HealthChannel,
+    pub(crate) mongodb_health_channel: db::HealthChannel,
+    pub(crate) queues_channel: QueuesChannel,
+    pub(crate) active_channels: ActiveChannels,
+    /// Versions of channels, in full publication mode.
+    pub(crate) versions: Option<Versions>,
+    pub(crate) change_stream_status: ChangeStreamStatusReceiver,
     pub(crate) current_data_channel: CurrentDataChannel,
+    pub(crate) settings: SettingsReceiver,
+    pub(crate) client_certificate_required: bool,
+    pub(crate) proxy_auth: Option<Arc<ProxyAuth>>,
+    pub(crate) admin: Option<Arc<Admin>>,
 }
 
 pub(crate) fn app(state: AppState) -> Router {
-    Router::new()
+    let mut subscribe_route = routing::post(centrifugo_subscribe_handler);
+    if state.proxy_auth.is_some() {
+        subscribe_route = subscribe_route.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            authenticate_proxy_request,
+        ));
+    }
+    if state.client_certificate_required {
+        subscribe_route =
+            subscribe_route.route_layer(middleware::from_fn(require_client_certificate));
+    }
+    let mut router = Router::new()
+        .route("/livez", routing::get(liveness_handler))
+        .route("/readyz", routing::get(readiness_handler))
         .route("/health", routing::get(health_handler))
-        .route(
-            "/centrifugo/subscribe",
-            routing::post(centrifugo_subscribe_handler),
+        .route("/metrics", routing::get(metrics_handler))
+        .route("/centrifugo/subscribe", subscribe_route);
+    if state.admin.is_some() {
+        router = router.route(
+            "/admin/replay",
+            routing::post(replay_handler).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                authenticate_admin_request,
+            )),
+        );
+    }
+    router.with_state(state)
+}
+
+async fn require_client_certificate(request: Request, next: Next) -> Response {
+    match request.extensions().get::<ConnectInfo<ConnectionInfo>>() {
+        Some(ConnectInfo(info)) if info.client_certificate => next.run(request).await,
+        _ => (StatusCode::FORBIDDEN, "client certificate required").into_response(),
+    }
+}
+
+#[instrument(skip_all)]
+async fn authenticate_proxy_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(proxy_auth) = &state.proxy_auth else {
+        return next.run(request).await;
+    };
+    let expected = match proxy_auth.token.get() {
+        Ok(token) => token,
+        Err(err) => {
+            error!(kind = "reading proxy authentication token", %err);
+            return INTERNAL_ERROR.into_response();
+        }
+    };
+    let authenticated = request
+        .headers()
+        .get(&proxy_auth.header)
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()));
+    if !authenticated {
+        error!(kind = "invalid proxy authentication token");
+        return (
+            StatusCode::UNAUTHORIZED,
+            "invalid proxy authentication token",
         )
-        .with_state(state)
+            .into_response();
+    }
+    next.run(request).await
+}
+
+#[instrument(skip_all)]
+async fn authenticate_admin_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(admin) = &state.admin else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let expected = match admin.token.get() {
+        Ok(token) => token,
+        Err(err) => {
+            error!(kind = "reading administration token", %err);
+            return INTERNAL_ERROR.into_response();
+        }
+    };
+    let authenticated = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+        .is_some_and(|token| constant_time_eq(token, expected.as_bytes()));
+    if !authenticated {
+        error!(kind = "invalid administration token");
+        return (StatusCode::UNAUTHORIZED, "invalid administration token").into_response();
+    }
+    next.run(request).await
+}
+
+/// Outcome of health checks.
+struct HealthChecks {
+    mongodb: bool,
+    change_stream: ChangeStreamStatus,
+    centrifugo: Option<Vec<ClusterHealth>>,
+    queues: Option<QueueLevels>,
+    active_channels: Option<usize>,
+}
+
+impl HealthChecks {
+    async fn run(state: &AppState) -> Self {
+        let (mongodb, centrifugo, queues) = tokio::join!(
+            state.mongodb_health_channel.roundtrip(()),
+            state.centrifugo_health_channel.roundtrip(()),
+            state.queues_channel.roundtrip(()),
+        );
+        Self {
+            mongodb: mongodb.unwrap_or_else(|err| {
+                error!(kind = "MongoDB health channel roundtrip", %err);
+                false
+            }),
+            change_stream: state.change_stream_status.borrow().clone(),
+            centrifugo: centrifugo
+                .map_err(|err| {
+                    error!(kind = "Centrifugo health channel roundtrip", %err);
+                })
+                .ok(),
+            queues: queues
+                .map_err(|err| {
+                    error!(kind = "queues channel roundtrip", %err);
+                })
+                .ok(),
+            active_channels: state.active_channels.count(),
+        }
+    }
+
+    fn change_stream_healthy(&self) -> bool {
+        self.change_stream.state == ChangeStreamState::Running
+    }
+
+    fn unhealthy_clusters(&self) -> Option<Vec<&str>> {
+        self.centrifugo.as_ref().map(|clusters| {
+            clusters
+                .iter()
+                .filter(|cluster| !cluster.healthy())
+                .map(|cluster| cluster.name.as_ref())
+                .collect()
+        })
+    }
+
+    fn centrifugo_healthy(&self) -> bool {
+        self.unhealthy_clusters()
+            .is_some_and(|unhealthy| unhealthy.is_empty())
+    }
+
+    /// Returns descriptions of failed checks.
+    fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if !self.mongodb {
+            failures.push("MongoDB unreachable".to_string());
+        }
+        if !self.change_stream_healthy() {
+            failures.push(format!(
+                "change stream {}",
+                self.change_stream.state.as_str()
+            ));
+        }
+        match self.unhealthy_clusters() {
+            Some(unhealthy) if unhealthy.is_empty() => {}
+            Some(unhealthy) => failures.push(format!(
+                "unhealthy Centrifugo clusters: {}",
+                unhealthy.join(", ")
+            )),
+            None => failures.push("Centrifugo health unknown".to_string()),
+        }
+        failures
+    }
+
+    fn report(&self, healthy: bool) -> Value {
+        let queue_level = |level: &QueueLevel| {
+            json!({
+                "length": level.length,
+                "capacity": level.capacity,
+            })
+        };
+        json!({
+            "healthy": healthy,
+            "mongodb": {
+                "healthy": self.mongodb,
+            },
+            "change_stream": {
+                "healthy": self.change_stream_healthy(),
+                "state": self.change_stream.state.as_str(),
+                "last_event": self
+                    .change_stream
+                    .last_event
+                    .map(|time| humantime::format_rfc3339_millis(time).to_string()),
+            },
+            "centrifugo": {
+                "healthy": self.centrifugo_healthy(),
+                "clusters": self.centrifugo.as_ref().map(|clusters| {
+                    clusters
+                        .iter()
+                        .map(cluster_report)
+                        .collect::<Vec<_>>()
+                }),
+            },
+            "queues": self.queues.as_ref().map(|queues| {
+                json!({
+                    "tags_update": queue_level(&queues.tags_update),
+                    "clusters": queues
+                        .clusters
+                        .iter()
+                        .map(|(name, level)| {
+                            let mut value = queue_level(level);
+                            value["name"] = json!(name.as_ref());
+                            value
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            }),
+            "subscriptions": {
+                "active_channels": self.active_channels,
+            },
+        })
+    }
 }
 
+fn cluster_report(cluster: &ClusterHealth) -> Value {
+    let versions = cluster.nodes.as_ref().map(|nodes| {
+        let mut versions = nodes
+            .iter()
+            .map(|node| node.version.as_str())
+            .collect::<Vec<_>>();
+        versions.sort_unstable();
+        versions.dedup();
+        versions
+    });
+    json!({
+        "name": cluster.name.as_ref(),
+        "healthy": cluster.healthy(),
+        "node_count": cluster.nodes.as_ref().map(Vec::len),
+        "versions": versions,
+    })
+}
+
+/// Reports whether the service is alive, i.e. the change stream has not failed.
+#[instrument(name = "liveness_api_handler", skip_all)]
+async fn liveness_handler(State(state): State<AppState>) -> Response {
+    if state.change_stream_status.borrow().state == ChangeStreamState::Failed {
+        (StatusCode::SERVICE_UNAVAILABLE, "change stream failed").into_response()
+    } else {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+/// Reports whether the service is ready, i.e. all health checks pass.
+#[instrument(name = "readiness_api_handler", skip_all)]
+async fn readiness_handler(State(state): State<AppState>) -> Response {
+    let failures = HealthChecks::run(&state).await.failures();
+    if failures.is_empty() {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, failures.join("; ")).into_response()
+    }
+}
+
+/// Reports the detailed outcome of health checks.
 #[instrument(name = "health_api_handler", skip_all)]
-async fn health_handler(State(state): State<AppState>) -> Result<StatusCode, StatusWithText> {
-    state
-        .health_channel
-        .roundtrip(())
-        .await
-        .map_err(|err| {
-            error!(kind = "health channel roundtrip", %err);
-            INTERNAL_ERROR
-        })?
-        .then_some(StatusCode::NO_CONTENT)
-        .ok_or(INTERNAL_ERROR)
+async fn health_handler(State(state): State<AppState>) -> Response {
+    let checks = HealthChecks::run(&state).await;
+    let healthy = checks.failures().is_empty();
+    let status_code = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status_code, Json(checks.report(healthy))).into_response()
+}
+
+/// Reports metrics of subscription tracking, in Prometheus text format.
+#[instrument(name = "metrics_api_handler", skip_all)]
+async fn metrics_handler(State(state): State<AppState>) -> Response {
+    let mut metrics = String::new();
+    if let Some(count) = state.active_channels.count() {
+        metrics += "# HELP centrifugo_change_stream_active_channels Channels having subscribers.\n";
+        metrics += "# TYPE centrifugo_change_stream_active_channels gauge\n";
+        metrics += &format!("centrifugo_change_stream_active_channels {count}\n");
+    }
+    metrics += "# HELP centrifugo_change_stream_skipped_publications_total Publications skipped for lack of subscribers.\n";
+    metrics += "# TYPE centrifugo_change_stream_skipped_publications_total counter\n";
+    metrics += &format!(
+        "centrifugo_change_stream_skipped_publications_total {}\n",
+        state.active_channels.skipped()
+    );
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics).into_response()
+}
+
+/// Returns the initial data of a subscription, along with the current version of the channel
+/// if any, transformed by the script if any.
+pub(crate) fn initial_data(
+    transform: Option<&Transform>,
+    channel: &str,
+    data: Option<MongoDBData>,
+    version: Option<u64>,
+) -> Result<Value, String> {
+    let mut data = json!(EnsureObject(data));
+    if let Some(version) = version {
+        data["version"] = json!(version);
+    }
+    match transform {
+        Some(transform) => transform.initial_data(channel, &data),
+        None => Ok(data),
+    }
 }
 
 #[instrument(name = "centrifugo_subscribe_api_handler", skip_all)]
@@ -102,6 +503,12 @@ async fn centrifugo_subscribe_handler(
     let Some(channel_name) = req.channel.strip_prefix(state.namespace_prefix.as_ref()) else {
         return Ok(CentrifugoProxyError::BadChannelNamespace.into());
     };
+    state.active_channels.insert(&req.channel);
+    // Read before the data, which is then at least as recent as the version.
+    let version = state
+        .versions
+        .as_ref()
+        .map(|versions| versions.current(&req.channel));
 
     let Ok(data) = state
         .current_data_channel
@@ -111,11 +518,19 @@ async fn centrifugo_subscribe_handler(
             error!(kind = "current data channel roundtrip", %err);
             INTERNAL_ERROR
         })?
-        .map(EnsureObject)
     else {
         return Ok(CentrifugoProxyError::InternalError.into());
     };
 
+    let transform = state.settings.borrow().transform.clone();
+    let data = match initial_data(transform.as_deref(), &req.channel, data, version) {
+        Ok(data) => data,
+        Err(err) => {
+            error!(kind = "transform script", %err);
+            return Ok(CentrifugoProxyError::InternalError.into());
+        }
+    };
+
     let resp_json = json!({
         "result": {
             "data": data
@@ -126,82 +541,439 @@ async fn centrifugo_subscribe_handler(
     Ok(Json(resp_json))
 }
 
+#[derive(Debug, Deserialize)]
+struct ReplayRequest {
+    from: String,
+    to: Option<String>,
+}
+
+#[instrument(skip_all)]
+async fn replay_handler(State(state): State<AppState>, Json(req): Json<ReplayRequest>) -> Response {
+    let Some(admin) = &state.admin else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let start = match req.from.parse::<ReplayStart>() {
+        Ok(start) => start,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let end = match req.to.as_deref().map(replay::parse_timestamp).transpose() {
+        Ok(end) => end,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let Ok(guard) = Arc::clone(&admin.replay_lock).try_lock_owned() else {
+        return (StatusCode::CONFLICT, "a replay is already running").into_response();
+    };
+
+    let admin = Arc::clone(admin);
+    let settings = state.settings.borrow().clone();
+    tokio::spawn(
+        async move {
+            let _guard = guard;
+            match replay::replay(
+                &admin.collection,
+                &admin.mongodb_config,
+                admin.publication_mode,
+                &settings,
+                &start,
+                end,
+            )
+            .await
+            {
+                Ok(outcome) => info!(
+                    msg = "replay done",
+                    replayed = outcome.replayed,
+                    failures = outcome.failures,
+                    resume_token = ?outcome.resume_token,
+                ),
+                Err(err) => error!(kind = "replay", err = format!("{err:#}")),
+            }
+        }
+        .instrument(info_span!("admin_replay")),
+    );
+
+    (StatusCode::ACCEPTED, "replay started").into_response()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
     use axum::body::{Body, to_bytes};
     use axum::http::Request;
+    use tokio::sync::watch;
     use tower::ServiceExt;
 
-    use crate::channel::roundtrip_channel;
+    use crate::centrifugo::NodeInfo;
+    use crate::channel::{RoundtripSender, roundtrip_channel};
+    use crate::settings::testing::{settings, settings_receiver};
 
     use super::*;
 
-    mod health_handler {
+    fn testing_state(current_data_channel: CurrentDataChannel) -> AppState {
+        let (centrifugo_health_channel, _) = roundtrip_channel(1);
+        let (mongodb_health_channel, _) = roundtrip_channel(1);
+        let (queues_channel, _) = roundtrip_channel(1);
+        AppState {
+            namespace_prefix: Arc::from("ns"),
+            centrifugo_health_channel,
+            mongodb_health_channel,
+            queues_channel,
+            active_channels: Default::default(),
+            versions: None,
+            change_stream_status: change_stream_status(ChangeStreamState::Running),
+            current_data_channel,
+            settings: settings_receiver(&[]),
+            client_certificate_required: false,
+            proxy_auth: None,
+            admin: None,
+        }
+    }
+
+    fn change_stream_status(state: ChangeStreamState) -> ChangeStreamStatusReceiver {
+        watch::channel(ChangeStreamStatus {
+            state,
+            last_event: Some(UNIX_EPOCH + Duration::from_secs(1673598600)),
+        })
+        .1
+    }
+
+    /// Returns a roundtrip sender which gets the reply once.
+    fn replying<S, R>(reply: R) -> RoundtripSender<S, R>
+    where
+        S: Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, mut rx) = roundtrip_channel(1);
+        tokio::spawn(async move {
+            let (_, response_tx) = rx.recv().await.expect("channel has been closed");
+            response_tx.send(reply).ok();
+        });
+        tx
+    }
+
+    fn cluster_health(name: &str, healthy: bool) -> ClusterHealth {
+        let node = |name: &str| NodeInfo {
+            name: name.to_string(),
+            version: "6.1.0".to_string(),
+        };
+        ClusterHealth {
+            name: name.into(),
+            nodes: healthy.then(|| vec![node("node1"), node("node2")]),
+        }
+    }
+
+    fn healthy_state() -> AppState {
+        let (current_data_channel, _) = roundtrip_channel(1);
+        AppState {
+            centrifugo_health_channel: replying(vec![
+                cluster_health("first", true),
+                cluster_health("second", true),
+            ]),
+            mongodb_health_channel: replying(true),
+            queues_channel: replying(QueueLevels {
+                tags_update: QueueLevel {
+                    length: 1,
+                    capacity: 10,
+                },
+                clusters: vec![(
+                    "first".into(),
+                    QueueLevel {
+                        length: 2,
+                        capacity: 10,
+                    },
+                )],
+            }),
+            ..testing_state(current_data_channel)
+        }
+    }
+
+    fn get_request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    mod liveness_handler {
         use super::*;
 
-        fn testing_fixture(health_channel: HealthChannel) -> (Router, Request<Body>) {
-            let (current_data_channel, _) = roundtrip_channel(1);
-            let app = app(AppState {
-                namespace_prefix: Arc::from(""),
-                health_channel,
-                current_data_channel,
-            });
-            let req = Request::builder()
-                .uri("/health")
-                .body(Body::empty())
+        #[tokio::test]
+        async fn alive() {
+            let (tx, _) = roundtrip_channel(1);
+            let res = app(testing_state(tx))
+                .oneshot(get_request("/livez"))
+                .await
                 .unwrap();
-            (app, req)
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
         }
 
+        #[tokio::test]
+        async fn change_stream_failed() {
+            let (tx, _) = roundtrip_channel(1);
+            let state = AppState {
+                change_stream_status: change_stream_status(ChangeStreamState::Failed),
+                ..testing_state(tx)
+            };
+            let res = app(state).oneshot(get_request("/livez")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    mod readiness_handler {
+        use super::*;
+
         #[tokio::test]
         async fn roundtrip_error() {
             let (tx, _) = roundtrip_channel(1);
-            let (app, req) = testing_fixture(tx);
-            let res = app.oneshot(req).await.unwrap();
-            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let res = app(testing_state(tx))
+                .oneshot(get_request("/readyz"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(body, "MongoDB unreachable; Centrifugo health unknown");
         }
 
         #[tokio::test]
-        async fn health_error() {
-            let (tx, mut rx) = roundtrip_channel(1);
-            let (app, req) = testing_fixture(tx);
-            tokio::spawn(async move {
-                let (_, response_tx) = rx.recv().await.expect("channel has been closed");
-                response_tx.send(false).expect("error sending response");
-            });
-            let res = app.oneshot(req).await.unwrap();
-            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        async fn not_ready() {
+            let state = AppState {
+                centrifugo_health_channel: replying(vec![
+                    cluster_health("first", true),
+                    cluster_health("second", false),
+                ]),
+                change_stream_status: change_stream_status(ChangeStreamState::Stopped),
+                ..healthy_state()
+            };
+            let res = app(state).oneshot(get_request("/readyz")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(
+                body,
+                "change stream stopped; unhealthy Centrifugo clusters: second"
+            );
         }
 
         #[tokio::test]
-        async fn success() {
-            let (tx, mut rx) = roundtrip_channel(1);
-            let (app, req) = testing_fixture(tx);
-            tokio::spawn(async move {
-                let (_, response_tx) = rx.recv().await.expect("channel has been closed");
-                response_tx.send(true).expect("error sending response");
-            });
-            let res = app.oneshot(req).await.unwrap();
+        async fn ready() {
+            let res = app(healthy_state())
+                .oneshot(get_request("/readyz"))
+                .await
+                .unwrap();
             assert_eq!(res.status(), StatusCode::NO_CONTENT);
         }
     }
 
+    mod health_handler {
+        use super::*;
+
+        async fn report(res: Response) -> Value {
+            let body = to_bytes(res.into_body(), 4096).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        #[tokio::test]
+        async fn roundtrip_error() {
+            let (tx, _) = roundtrip_channel(1);
+            let res = app(testing_state(tx))
+                .oneshot(get_request("/health"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let report = report(res).await;
+            assert_eq!(report["healthy"], false);
+            assert_eq!(report["mongodb"]["healthy"], false);
+            assert_eq!(report["change_stream"]["healthy"], true);
+            assert_eq!(
+                report["centrifugo"],
+                json!({ "healthy": false, "clusters": null })
+            );
+            assert_eq!(report["queues"], Value::Null);
+        }
+
+        #[tokio::test]
+        async fn unhealthy() {
+            let state = AppState {
+                mongodb_health_channel: replying(false),
+                ..healthy_state()
+            };
+            let res = app(state).oneshot(get_request("/health")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let report = report(res).await;
+            assert_eq!(report["healthy"], false);
+            assert_eq!(report["mongodb"]["healthy"], false);
+            assert_eq!(report["centrifugo"]["healthy"], true);
+        }
+
+        #[tokio::test]
+        async fn healthy() {
+            let res = app(healthy_state())
+                .oneshot(get_request("/health"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "application/json");
+            assert_eq!(
+                report(res).await,
+                json!({
+                    "healthy": true,
+                    "mongodb": { "healthy": true },
+                    "change_stream": {
+                        "healthy": true,
+                        "state": "running",
+                        "last_event": "2023-01-13T08:30:00.000Z",
+                    },
+                    "centrifugo": {
+                        "healthy": true,
+                        "clusters": [
+                            {
+                                "name": "first",
+                                "healthy": true,
+                                "node_count": 2,
+                                "versions": ["6.1.0"],
+                            },
+                            {
+                                "name": "second",
+                                "healthy": true,
+                                "node_count": 2,
+                                "versions": ["6.1.0"],
+                            },
+                        ],
+                    },
+                    "queues": {
+                        "tags_update": { "length": 1, "capacity": 10 },
+                        "clusters": [
+                            { "name": "first", "length": 2, "capacity": 10 },
+                        ],
+                    },
+                    "subscriptions": { "active_channels": null },
+                })
+            );
+        }
+    }
+
+    mod metrics_handler {
+        use super::*;
+
+        async fn metrics(state: AppState) -> String {
+            let res = app(state).oneshot(get_request("/metrics")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["Content-Type"], "text/plain; version=0.0.4");
+            let body = to_bytes(res.into_body(), 4096).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+
+        #[tokio::test]
+        async fn unknown_active_channels() {
+            let metrics = metrics(healthy_state()).await;
+            assert!(!metrics.contains("centrifugo_change_stream_active_channels"));
+            assert!(metrics.contains("\ncentrifugo_change_stream_skipped_publications_total 0\n"));
+        }
+
+        #[tokio::test]
+        async fn known_active_channels() {
+            let active_channels = ActiveChannels::known(&["ns:first", "ns:second"]);
+            active_channels.record_skipped();
+            let metrics = metrics(AppState {
+                active_channels,
+                ..healthy_state()
+            })
+            .await;
+            assert!(metrics.contains("\ncentrifugo_change_stream_active_channels 2\n"));
+            assert!(metrics.contains("\ncentrifugo_change_stream_skipped_publications_total 1\n"));
+        }
+    }
+
     mod centrifugo_subscribe_handler {
         use mongodb::bson::{Bson, DateTime};
 
         use crate::model::MongoDBData;
+        use crate::script::Transform;
+        use crate::settings::Settings;
 
         use super::*;
 
         fn testing_app(current_data_channel: CurrentDataChannel) -> Router {
-            let (health_channel, _) = roundtrip_channel(1);
+            app(testing_state(current_data_channel))
+        }
+
+        fn subscribe_request() -> Request<Body> {
+            Request::post("/centrifugo/subscribe")
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    r#"{"protocol":"json","encoding":"unsupported","channel":"ns:chan"}"#,
+                ))
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn missing_client_certificate() {
+            let (tx, _) = roundtrip_channel(1);
+            let app = app(AppState {
+                client_certificate_required: true,
+                ..testing_state(tx)
+            });
+            let mut req = subscribe_request();
+            req.extensions_mut().insert(ConnectInfo(ConnectionInfo {
+                client_certificate: false,
+            }));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        }
+
+        fn proxy_auth_app(current_data_channel: CurrentDataChannel) -> Router {
+            let proxy_auth = ProxyAuth {
+                header: HeaderName::from_static("x-proxy-token"),
+                token: Secret::from("sometoken"),
+            };
             app(AppState {
-                namespace_prefix: Arc::from("ns"),
-                health_channel,
-                current_data_channel,
+                proxy_auth: Some(Arc::new(proxy_auth)),
+                ..testing_state(current_data_channel)
             })
         }
 
+        #[tokio::test]
+        async fn missing_proxy_token() {
+            let (tx, mut rx) = roundtrip_channel(1);
+            let res = proxy_auth_app(tx)
+                .oneshot(subscribe_request())
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(rx.try_recv().is_err());
+        }
+
+        #[tokio::test]
+        async fn invalid_proxy_token() {
+            let (tx, _) = roundtrip_channel(1);
+            let mut req = subscribe_request();
+            req.headers_mut()
+                .insert("X-Proxy-Token", "othertoken".parse().unwrap());
+            let res = proxy_auth_app(tx).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn valid_proxy_token() {
+            let (tx, _) = roundtrip_channel(1);
+            let mut req = subscribe_request();
+            req.headers_mut()
+                .insert("X-Proxy-Token", "sometoken".parse().unwrap());
+            let res = proxy_auth_app(tx).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        #[tokio::test]
+        async fn client_certificate() {
+            let (tx, _) = roundtrip_channel(1);
+            let app = app(AppState {
+                client_certificate_required: true,
+                ..testing_state(tx)
+            });
+            let mut req = subscribe_request();
+            req.extensions_mut().insert(ConnectInfo(ConnectionInfo {
+                client_certificate: true,
+            }));
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
         #[tokio::test]
         async fn unsupported_protocol() {
             let (tx, _) = roundtrip_channel(1);
@@ -321,6 +1093,29 @@ mod tests {
             assert_eq!(body, r#"{"result":{"data":{}}}"#);
         }
 
+        #[tokio::test]
+        async fn success_with_version() {
+            let (tx, mut rx) = roundtrip_channel(1);
+            let app = app(AppState {
+                versions: Some(Versions::known(&[("ns:chan", 3)])),
+                ..testing_state(tx)
+            });
+            tokio::spawn(async move {
+                let (_, response_tx) = rx.recv().await.unwrap();
+                response_tx.send(Ok(None)).unwrap();
+            });
+            let req = Request::post("/centrifugo/subscribe")
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    r#"{"protocol":"json","encoding":"json","channel":"ns:chan"}"#,
+                ))
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(body, r#"{"result":{"data":{"version":3}}}"#);
+        }
+
         #[tokio::test]
         async fn success_with_data() {
             let (tx, mut rx) = roundtrip_channel(1);
@@ -354,5 +1149,134 @@ mod tests {
             assert!(body.contains(r#""one":"2023-01-13T08:30:00Z""#));
             assert!(body.contains(r#""two":"1984-12-09T03:30:00Z""#));
         }
+
+        #[tokio::test]
+        async fn success_transformed() {
+            let (tx, mut rx) = roundtrip_channel(1);
+            let transform = Transform::compile(
+                "fn subscribe(channel, data) { #{ channel: channel, first: data.val.first } }",
+            )
+            .unwrap();
+            let settings = Settings {
+                transform: Some(Arc::new(transform)),
+                ..settings(&[])
+            };
+            let app = app(AppState {
+                settings: watch::channel(Arc::new(settings)).1,
+                ..testing_state(tx)
+            });
+            let mut tags_update_data = MongoDBData::with_capacity(1);
+            tags_update_data.insert_value("first".into(), Bson::Int32(9));
+            tokio::spawn(async move {
+                let (_, response_tx) = rx.recv().await.unwrap();
+                response_tx.send(Ok(Some(tags_update_data))).unwrap();
+            });
+            let req = Request::post("/centrifugo/subscribe")
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    r#"{"protocol":"json","encoding":"json","channel":"ns:chan"}"#,
+                ))
+                .unwrap();
+            let res = app.oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = to_bytes(res.into_body(), 1024).await.unwrap();
+            assert_eq!(
+                body,
+                r#"{"result":{"data":{"channel":"ns:chan","first":9}}}"#
+            );
+        }
+    }
+
+    mod replay_handler {
+        use super::*;
+
+        async fn admin() -> Arc<Admin> {
+            let mongodb_config = db::testing::config(&["--mongodb-uri=mongodb://localhost"]);
+            let collection = db::create_collection(&mongodb_config).await.unwrap();
+            Arc::new(Admin {
+                token: Secret::from("admintoken"),
+                collection,
+                mongodb_config,
+                publication_mode: PublicationMode::Delta,
+                replay_lock: Default::default(),
+            })
+        }
+
+        fn admin_app(admin: Arc<Admin>) -> Router {
+            let (tx, _) = roundtrip_channel(1);
+            app(AppState {
+                admin: Some(admin),
+                ..testing_state(tx)
+            })
+        }
+
+        fn replay_request(token: Option<&str>, body: &'static str) -> Request<Body> {
+            let mut builder =
+                Request::post("/admin/replay").header("Content-Type", "application/json");
+            if let Some(token) = token {
+                builder = builder.header("Authorization", format!("Bearer {token}"));
+            }
+            builder.body(Body::from(body)).unwrap()
+        }
+
+        #[tokio::test]
+        async fn disabled() {
+            let (tx, _) = roundtrip_channel(1);
+            let req = replay_request(Some("admintoken"), r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = app(testing_state(tx)).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn missing_token() {
+            let req = replay_request(None, r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = admin_app(admin().await).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn invalid_token() {
+            let req = replay_request(Some("othertoken"), r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = admin_app(admin().await).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn invalid_from() {
+            let req = replay_request(Some("admintoken"), r#"{"from":"yesterday"}"#);
+            let res = admin_app(admin().await).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn invalid_to() {
+            let req = replay_request(
+                Some("admintoken"),
+                r#"{"from":"2023-01-13T08:30:00Z","to":"8263C1192A000000012B0229296E04"}"#,
+            );
+            let res = admin_app(admin().await).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn already_running() {
+            let admin = admin().await;
+            let _guard = admin.replay_lock.lock().await;
+            let req = replay_request(Some("admintoken"), r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = admin_app(Arc::clone(&admin)).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+        }
+
+        #[tokio::test]
+        async fn started() {
+            let admin = admin().await;
+            let req = replay_request(Some("admintoken"), r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = admin_app(Arc::clone(&admin)).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::ACCEPTED);
+            // The replay keeps running in the background, waiting for MongoDB.
+            let req = replay_request(Some("admintoken"), r#"{"from":"2023-01-13T08:30:00Z"}"#);
+            let res = admin_app(admin).oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+        }
     }
 }
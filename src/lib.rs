@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use clap::Args;
 
@@ -7,4 +8,17 @@ pub struct CommonArgs {
     /// Address to listen on
     #[arg(env, long, default_value = "0.0.0.0:8080")]
     pub listen_address: SocketAddr,
+
+    /// PEM file containing the TLS certificate chain of the HTTP API (enables TLS)
+    #[arg(env, long)]
+    pub listen_tls_certificate_file: Option<PathBuf>,
+
+    /// PEM file containing the TLS private key of the HTTP API
+    #[arg(env, long)]
+    pub listen_tls_key_file: Option<PathBuf>,
+}
+
+/// Installs the process-wide TLS cryptography provider, if not already done.
+pub fn install_crypto_provider() {
+    let _ = tokio_rustls::rustls::crypto::ring::default_provider().install_default();
 }
@@ -1,56 +1,165 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Context as _;
+use anyhow::{Context as _, anyhow, bail};
 use clap::Parser;
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use futures_util::StreamExt;
-use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::consts::{SIGHUP, TERM_SIGNALS};
 use signal_hook::low_level::signal_name;
 use signal_hook_tokio::Signals;
 use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
 use tracing::{Instrument, error, info, info_span, instrument};
 
 use centrifugo_change_stream::CommonArgs;
 
+mod admin;
+mod cache;
 mod centrifugo;
 mod channel;
+mod config;
 mod db;
+mod delivery;
+mod filter;
 mod http_api;
 mod model;
+mod replay;
+mod resync;
+mod script;
+mod secret;
+mod settings;
+mod subscriptions;
+mod throttle;
+mod tls;
 
 #[derive(Parser)]
 struct Args {
+    /// Path of a TOML configuration file
+    #[arg(env, long)]
+    config: Option<PathBuf>,
+
     #[command(flatten)]
     common: CommonArgs,
 
+    #[command(flatten)]
+    tls: tls::Config,
+
+    #[command(flatten)]
+    http_api: http_api::Config,
+
     #[command(flatten)]
     centrifugo: centrifugo::Config,
 
     #[command(flatten)]
     mongodb: db::Config,
 
+    #[command(flatten)]
+    filter: filter::Config,
+
+    #[command(flatten)]
+    throttle: throttle::Config,
+
+    #[command(flatten)]
+    script: script::Config,
+
+    #[command(flatten)]
+    resync: resync::Config,
+
+    #[command(flatten)]
+    subscriptions: subscriptions::Config,
+
     /// Size of the tags update channel buffer
     #[arg(env, long, value_parser = clap::value_parser!(u8).range(1..), default_value = "10")]
     tags_update_buffer: u8,
 
+    /// Maximum duration for sending queued publications on shutdown (e.g. `10s`)
+    #[arg(env, long, value_parser = humantime::parse_duration, default_value = "10s")]
+    shutdown_timeout: Duration,
+
+    /// Content of the publications sent to Centrifugo
+    #[arg(env, long, value_enum, default_value_t)]
+    publication_mode: model::PublicationMode,
+
     #[command(flatten)]
     verbosity: Verbosity<InfoLevel>,
+
+    #[command(subcommand)]
+    command: Option<admin::Command>,
 }
 
+impl Args {
+    /// Parses arguments from command line, environment and configuration file.
+    fn load() -> Result<Self, clap::Error> {
+        config::parse_args_from(std::env::args_os())
+    }
+
+    fn settings(&self) -> anyhow::Result<settings::Settings> {
+        settings::Settings::new(&self.centrifugo, &self.filter, &self.throttle, &self.script)
+    }
+}
+
+/// Reloads settings from command line arguments, environment and configuration file.
 #[instrument(skip_all)]
-async fn handle_signals(signals: Signals, shutdown_token: CancellationToken) {
+fn reload_settings(settings_tx: &watch::Sender<Arc<settings::Settings>>) {
+    let settings = match Args::load() {
+        Ok(args) => args.settings(),
+        Err(err) => Err(anyhow!(
+            "{}",
+            err.to_string().lines().next().unwrap_or_default()
+        )),
+    };
+    match settings {
+        Ok(settings) => {
+            settings_tx.send_replace(Arc::new(settings));
+            info!(status = "success");
+        }
+        Err(err) => {
+            error!(kind = "loading settings", err = format!("{err:#}"));
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn handle_signals(
+    mut signals: Signals,
+    shutdown_token: CancellationToken,
+    settings_tx: watch::Sender<Arc<settings::Settings>>,
+) {
     info!(status = "started");
-    let mut signals_stream = signals.map(|signal| signal_name(signal).unwrap_or("unknown"));
-    while let Some(signal) = signals_stream.next().await {
-        info!(msg = "received signal", reaction = "shutting down", signal);
-        shutdown_token.cancel();
+    while let Some(signal) = signals.next().await {
+        let signal_name = signal_name(signal).unwrap_or("unknown");
+        if signal == SIGHUP {
+            info!(
+                msg = "received signal",
+                reaction = "reloading settings",
+                signal = signal_name
+            );
+            reload_settings(&settings_tx);
+        } else {
+            info!(
+                msg = "received signal",
+                reaction = "shutting down",
+                signal = signal_name
+            );
+            shutdown_token.cancel();
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let args = Args::load().unwrap_or_else(|err| err.exit());
+
+    if let Some(command) = &args.command {
+        tracing_subscriber::fmt()
+            .with_max_level(args.verbosity)
+            .with_writer(std::io::stderr)
+            .init();
+        return command.run(&args).await;
+    }
 
     tracing_subscriber::fmt()
         .with_max_level(args.verbosity)
@@ -58,40 +167,105 @@ async fn main() -> anyhow::Result<()> {
 
     let shutdown_token = CancellationToken::new();
 
-    let signals = Signals::new(TERM_SIGNALS).context("error registering termination signals")?;
+    let (tls_acceptor, certificate_reloader) = tls::acceptor(&args.common, &args.tls)?.unzip();
+    let (settings_tx, settings_rx) = watch::channel(Arc::new(args.settings()?));
+
+    let signals =
+        Signals::new(TERM_SIGNALS.iter().chain(&[SIGHUP])).context("error registering signals")?;
     let signals_handle = signals.handle();
-    let signals_task = tokio::spawn(handle_signals(signals, shutdown_token.clone()));
+    let signals_task = tokio::spawn(handle_signals(signals, shutdown_token.clone(), settings_tx));
+    let certificate_reload_task =
+        tls::handle_certificate_reload(certificate_reloader, shutdown_token.clone());
 
-    let centrifugo_client = centrifugo::Client::new(&args.centrifugo);
-    let (tags_update_channel, tags_update_task) =
-        centrifugo_client.handle_tags_update(args.tags_update_buffer.into());
-    let (health_channel, health_task) = centrifugo_client.handle_health();
+    let deliveries = delivery::Deliveries::new(db::load_resume_token(&args.mongodb)?);
+    let active_channels = subscriptions::ActiveChannels::default();
+    let versions =
+        (args.publication_mode == model::PublicationMode::Full).then(centrifugo::Versions::default);
+    let (tags_update_channel, queues_channel, tags_update_task) = centrifugo::handle_tags_update(
+        settings_rx.clone(),
+        args.tags_update_buffer.into(),
+        versions.clone(),
+        active_channels.clone(),
+        args.shutdown_timeout,
+        deliveries.clone(),
+    );
+    let (centrifugo_health_channel, centrifugo_health_task) =
+        centrifugo::handle_health(settings_rx.clone());
 
     let mongodb_collection = db::create_collection(&args.mongodb).await?;
-    let change_stream_task = mongodb_collection
-        .handle_change_stream(tags_update_channel, shutdown_token.clone())
+    let mongodb_credentials_task =
+        mongodb_collection.handle_credentials(&args.mongodb, shutdown_token.clone());
+    let lookup_cache = args.mongodb.lookup_cache()?;
+    let subscriptions_task = subscriptions::handle_subscriptions(
+        &args.subscriptions,
+        active_channels.clone(),
+        mongodb_collection.namespace(),
+        settings_rx.clone(),
+        shutdown_token.clone(),
+    );
+    let resync_task = resync::handle_resync(
+        &args.resync,
+        mongodb_collection.clone(),
+        &args.mongodb,
+        settings_rx.clone(),
+        tags_update_channel.clone(),
+        shutdown_token.clone(),
+    );
+    let (change_stream_status, change_stream_task) = mongodb_collection
+        .handle_change_stream(
+            &args.mongodb,
+            args.publication_mode,
+            tags_update_channel,
+            deliveries,
+            lookup_cache.clone(),
+            shutdown_token.clone(),
+        )
         .await?;
-    let (current_data_channel, current_data_task) = mongodb_collection.handle_current_data();
+    let (current_data_channel, current_data_task) =
+        mongodb_collection.handle_current_data(&args.mongodb, lookup_cache);
+    let (mongodb_health_channel, mongodb_health_task) = mongodb_collection.handle_health();
 
     let app = http_api::app(http_api::AppState {
         namespace_prefix: Arc::from(mongodb_collection.namespace() + ":"),
-        health_channel,
+        centrifugo_health_channel,
+        mongodb_health_channel,
+        queues_channel,
+        active_channels,
+        versions,
+        change_stream_status,
         current_data_channel,
+        settings: settings_rx,
+        client_certificate_required: args.tls.client_certificate_required(),
+        proxy_auth: http_api::ProxyAuth::new(&args.http_api)?.map(Arc::new),
+        admin: http_api::Admin::new(
+            &args.http_api,
+            mongodb_collection.clone(),
+            &args.mongodb,
+            args.publication_mode,
+        )?
+        .map(Arc::new),
     });
     async move {
         let listener = match TcpListener::bind(&args.common.listen_address).await {
             Ok(listener) => {
-                info!(addr = %args.common.listen_address, msg = "listening");
-                listener
+                info!(
+                    addr = %args.common.listen_address,
+                    tls = tls_acceptor.is_some(),
+                    msg = "listening"
+                );
+                tls::HttpListener::new(listener, tls_acceptor)
             }
             Err(err) => {
                 error!(kind="TCP listen", %err);
                 return;
             }
         };
-        if let Err(err) = axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(shutdown_token.cancelled_owned())
-            .await
+        if let Err(err) = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<tls::ConnectionInfo>(),
+        )
+        .with_graceful_shutdown(shutdown_token.cancelled_owned())
+        .await
         {
             error!(kind = "HTTP server", %err);
         }
@@ -102,15 +276,26 @@ async fn main() -> anyhow::Result<()> {
 
     signals_handle.close();
 
-    let (change_stream_task_result, ..) = tokio::try_join!(
+    let (change_stream_task_result, _, drained, ..) = tokio::try_join!(
         change_stream_task,
         signals_task,
         tags_update_task,
-        health_task,
+        centrifugo_health_task,
         current_data_task,
+        mongodb_health_task,
+        resync_task,
+        subscriptions_task,
+        mongodb_credentials_task,
+        certificate_reload_task,
     )
     .context("error joining tasks")?;
+    if let Some(resume_token) = &drained.resume_token {
+        db::save_resume_token(&args.mongodb, resume_token)?;
+    }
     change_stream_task_result?;
+    if drained.undelivered > 0 {
+        bail!("{} publication(s) undelivered", drained.undelivered);
+    }
 
     Ok(())
 }
@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use clap::ValueEnum;
 use mongodb::Namespace;
-use mongodb::bson::{Bson, DateTime};
+use mongodb::bson::{self, Bson, DateTime, Document, Timestamp};
+use mongodb::change_stream::event::ResumeToken;
 use serde::ser::{self, SerializeMap, Serializer};
 use serde::{Deserialize, Serialize};
 use tracing::{error, info_span};
 
+use crate::delivery::{Deliveries, Ticket};
+use crate::filter::Filter;
+
 #[derive(Deserialize)]
 #[serde(remote = "Namespace")]
 struct UpdateNamespace {
@@ -23,24 +28,153 @@ struct DocumentKey {
 #[serde(rename_all = "camelCase")]
 struct UpdateDescription {
     updated_fields: HashMap<String, Bson>,
+    #[serde(default)]
+    removed_fields: Vec<String>,
+}
+
+/// Content of the publications sent to Centrifugo.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum PublicationMode {
+    #[default]
+    Delta,
+    Full,
 }
 
 /// Custom change stream event, specialized for updates.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct UpdateEvent {
+    #[serde(rename = "_id", default)]
+    resume_token: Option<ResumeToken>,
     #[serde(with = "UpdateNamespace")]
     ns: Namespace,
     document_key: DocumentKey,
     update_description: UpdateDescription,
+    #[serde(default)]
+    full_document: Option<MongoDBData>,
+    #[serde(default)]
+    full_document_before_change: Option<MongoDBData>,
+    #[serde(default)]
+    cluster_time: Option<Timestamp>,
+    /// Delivery of the change, tracked from its reception.
+    #[serde(skip)]
+    ticket: Option<Ticket>,
 }
 
 impl UpdateEvent {
-    pub(crate) fn into_centrifugo(self) -> (String, MongoDBData) {
+    /// Builds an event publishing the full current data of a document, for resyncs.
+    pub(crate) fn resync(ns: Namespace, id: String, data: MongoDBData) -> Self {
+        Self {
+            resume_token: None,
+            ns,
+            document_key: DocumentKey { id },
+            update_description: UpdateDescription {
+                updated_fields: HashMap::new(),
+                removed_fields: Vec::new(),
+            },
+            full_document: Some(data),
+            full_document_before_change: None,
+            cluster_time: None,
+            ticket: None,
+        }
+    }
+
+    /// Starts tracking the delivery of the change, if it has a resume token (resync events
+    /// have none).
+    pub(crate) fn track(&mut self, deliveries: &Deliveries) {
+        self.ticket = self
+            .resume_token
+            .clone()
+            .map(|token| deliveries.track(token));
+    }
+
+    pub(crate) fn take_ticket(&mut self) -> Option<Ticket> {
+        self.ticket.take()
+    }
+
+    pub(crate) fn cluster_time(&self) -> Option<Timestamp> {
+        self.cluster_time
+    }
+
+    pub(crate) fn resume_token(&self) -> Option<&ResumeToken> {
+        self.resume_token.as_ref()
+    }
+
+    pub(crate) fn document_id(&self) -> &str {
+        &self.document_key.id
+    }
+
+    /// Applies the update to the current data of the document, returning `false` if the
+    /// outcome can not be known from the event alone (e.g. removed or nested fields).
+    pub(crate) fn apply_to(&self, data: &mut MongoDBData) -> bool {
+        if let Some(full_document) = &self.full_document {
+            *data = full_document.clone();
+            return true;
+        }
+        let is_data_field = |key: &str| {
+            key == "val" || key == "ts" || key.starts_with("val.") || key.starts_with("ts.")
+        };
+        if self
+            .update_description
+            .removed_fields
+            .iter()
+            .any(|key| is_data_field(key))
+        {
+            return false;
+        }
+        for (key, value) in &self.update_description.updated_fields {
+            if let Some(data_key) = key.strip_prefix("val.")
+                && !data_key.contains('.')
+            {
+                data.insert_value(data_key.into(), value.clone());
+            } else if let Some(ts_key) = key.strip_prefix("ts.")
+                && !ts_key.contains('.')
+                && let Bson::DateTime(date_time) = value
+            {
+                data.insert_timestamp(ts_key.into(), *date_time);
+            } else if is_data_field(key) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Converts the event into a channel and a publication, returning `None` if the filter
+    /// leaves nothing to publish.
+    pub(crate) fn into_centrifugo(self, filter: &mut Filter) -> Option<(String, Publication)> {
         let _entered = info_span!("update_event_into_centrifugo").entered();
 
-        let mut data = MongoDBData::with_capacity(self.update_description.updated_fields.len());
-        for (key, value) in self.update_description.updated_fields {
+        let channel = self.ns.to_string() + ":" + self.document_key.id.as_str();
+        let change = Change {
+            operation_type: if self.resume_token.is_some() {
+                "update"
+            } else {
+                "resync"
+            },
+            document_id: self.document_key.id,
+            updated_fields: self
+                .update_description
+                .updated_fields
+                .keys()
+                .cloned()
+                .collect(),
+            removed_fields: self.update_description.removed_fields,
+            cluster_time: self.cluster_time.map(|time| time.time),
+        };
+
+        let mut updated_fields = self.update_description.updated_fields;
+        let fields_count = updated_fields.len();
+        filter.retain_updated_fields(&channel, &mut updated_fields);
+        let filtered = updated_fields.len() < fields_count;
+
+        let prev = self.full_document_before_change.map(|before| {
+            let mut prev = before.updated_subset(&updated_fields);
+            prev.retain_fields(|field| filter.is_allowed(field));
+            prev
+        });
+
+        let mut data = MongoDBData::with_capacity(updated_fields.len());
+        for (key, value) in updated_fields {
             if let Some(data_key) = key.strip_prefix("val.") {
                 data.insert_value(data_key.into(), value);
             } else if let Some(ts_key) = key.strip_prefix("ts.") {
@@ -52,13 +186,81 @@ impl UpdateEvent {
             }
         }
 
-        let channel = self.ns.to_string() + ":" + self.document_key.id.as_str();
+        if filtered && data.is_empty() {
+            return None;
+        }
+
+        if let Some(mut full_document) = self.full_document {
+            full_document.retain_fields(|field| filter.is_allowed(field));
+            let publication = Publication::new(full_document, prev).with_change(change);
+            return Some((channel, publication));
+        }
+
+        Some((channel, Publication::new(data, prev).with_change(change)))
+    }
+}
 
-        (channel, data)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentChange {
+    document_key: DocumentKey,
+    #[serde(default)]
+    full_document: Option<MongoDBData>,
+}
+
+/// Change stream event of a watched operation.
+#[derive(Debug)]
+pub(crate) enum ChangeEvent {
+    Update(Box<UpdateEvent>),
+    /// The document was replaced by the given one.
+    Replace {
+        id: String,
+        data: Option<MongoDBData>,
+    },
+    Delete {
+        id: String,
+    },
+    /// The change stream ends, e.g. because the collection was dropped or renamed.
+    Invalidate,
+}
+
+impl ChangeEvent {
+    /// Returns the identifier of the changed document, or `None` if the event concerns the
+    /// whole collection.
+    pub(crate) fn document_id(&self) -> Option<&str> {
+        match self {
+            Self::Update(event) => Some(event.document_id()),
+            Self::Replace { id, .. } | Self::Delete { id } => Some(id),
+            Self::Invalidate => None,
+        }
+    }
+
+    /// Deserializes a change stream event, returning `None` for an operation which is not
+    /// watched.
+    pub(crate) fn from_document(document: Document) -> bson::error::Result<Option<Self>> {
+        let event = match document.get_str("operationType").unwrap_or_default() {
+            "update" => Self::Update(bson::deserialize_from_document(document)?),
+            "replace" => {
+                let change: DocumentChange = bson::deserialize_from_document(document)?;
+                Self::Replace {
+                    id: change.document_key.id,
+                    data: change.full_document,
+                }
+            }
+            "delete" => {
+                let change: DocumentChange = bson::deserialize_from_document(document)?;
+                Self::Delete {
+                    id: change.document_key.id,
+                }
+            }
+            "invalidate" => Self::Invalidate,
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug)]
 struct Rfc3339Date(DateTime);
 
 impl From<DateTime> for Rfc3339Date {
@@ -77,13 +279,45 @@ impl Serialize for Rfc3339Date {
     }
 }
 
+/// Values and timestamps of a document.
+///
+/// Deserialization is lenient, as for updated fields: a missing or invalid `val` or `ts` field,
+/// and timestamps which are not BSON dates, are logged and skipped.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", from = "Document")]
 pub(crate) struct MongoDBData {
     val: HashMap<String, Bson>,
     ts: HashMap<String, Rfc3339Date>,
 }
 
+impl From<Document> for MongoDBData {
+    fn from(mut document: Document) -> Self {
+        let mut field = |key| match document.remove(key) {
+            Some(Bson::Document(fields)) => fields,
+            None => Document::new(),
+            Some(value) => {
+                error!(kind = "not a BSON document", field = key, ?value);
+                Document::new()
+            }
+        };
+        let (val, ts) = (field("val"), field("ts"));
+        let mut data = Self::with_capacity(val.len());
+        data.val.extend(val);
+        for (key, value) in ts {
+            let Bson::DateTime(date_time) = value else {
+                error!(
+                    kind = "not a BSON DateTime",
+                    field = format!("ts.{key}"),
+                    ?value
+                );
+                continue;
+            };
+            data.insert_timestamp(key, date_time);
+        }
+        data
+    }
+}
+
 impl MongoDBData {
     pub(crate) fn with_capacity(capacity: usize) -> Self {
         Self {
@@ -99,6 +333,145 @@ impl MongoDBData {
     pub(crate) fn insert_timestamp(&mut self, k: String, v: DateTime) -> Option<DateTime> {
         self.ts.insert(k, v.into()).map(|d| d.0)
     }
+
+    fn is_empty(&self) -> bool {
+        self.val.is_empty() && self.ts.is_empty()
+    }
+
+    /// Retains only the values and timestamps of fields for which the predicate returns `true`.
+    fn retain_fields(&mut self, f: impl Fn(&str) -> bool) {
+        self.val.retain(|k, _| f(k));
+        self.ts.retain(|k, _| f(k));
+    }
+
+    /// Returns the values and timestamps corresponding to given updated fields.
+    fn updated_subset(&self, updated_fields: &HashMap<String, Bson>) -> Self {
+        let mut subset = Self::with_capacity(updated_fields.len());
+        for key in updated_fields.keys() {
+            if let Some(data_key) = key.strip_prefix("val.") {
+                if let Some(value) = self.val.get(data_key) {
+                    subset.val.insert(data_key.into(), value.clone());
+                }
+            } else if let Some(ts_key) = key.strip_prefix("ts.")
+                && let Some(date) = self.ts.get(ts_key)
+            {
+                subset.ts.insert(ts_key.into(), date.clone());
+            }
+        }
+        subset
+    }
+}
+
+/// Description of the changes a publication is built from, passed to the transform script.
+#[derive(Debug, Serialize)]
+pub(crate) struct Change {
+    /// `update`, or `resync` for the current data published by resyncs.
+    operation_type: &'static str,
+    document_id: String,
+    /// Updated fields in dot notation (e.g. `val.a`), before filtering.
+    updated_fields: Vec<String>,
+    removed_fields: Vec<String>,
+    /// Cluster time of the change, in seconds since the Unix epoch.
+    cluster_time: Option<u32>,
+}
+
+impl Change {
+    /// Merges a newer change of the same document into this one.
+    fn merge(&mut self, newer: Self) {
+        self.operation_type = newer.operation_type;
+        for field in newer.updated_fields {
+            if !self.updated_fields.contains(&field) {
+                self.updated_fields.push(field);
+            }
+        }
+        for field in newer.removed_fields {
+            if !self.removed_fields.contains(&field) {
+                self.removed_fields.push(field);
+            }
+        }
+        self.cluster_time = newer.cluster_time.or(self.cluster_time);
+    }
+}
+
+/// Data published to Centrifugo.
+#[derive(Debug, Serialize)]
+pub(crate) struct Publication {
+    #[serde(flatten)]
+    data: MongoDBData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev: Option<MongoDBData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    /// Deliveries of the changes merged into this publication.
+    #[serde(skip)]
+    tickets: Vec<Ticket>,
+    /// Changes merged into this publication, if built from change events.
+    #[serde(skip)]
+    change: Option<Change>,
+}
+
+impl Publication {
+    fn new(data: MongoDBData, prev: Option<MongoDBData>) -> Self {
+        Self {
+            data,
+            prev,
+            version: None,
+            tickets: Vec::new(),
+            change: None,
+        }
+    }
+
+    fn with_change(mut self, change: Change) -> Self {
+        self.change = Some(change);
+        self
+    }
+
+    pub(crate) fn change(&self) -> Option<&Change> {
+        self.change.as_ref()
+    }
+
+    pub(crate) fn attach(&mut self, ticket: Ticket) {
+        self.tickets.push(ticket);
+    }
+
+    pub(crate) fn tickets(&self) -> &[Ticket] {
+        &self.tickets
+    }
+
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = Some(version);
+    }
+
+    /// Merges a newer publication into this one, newer values overriding older ones, but older
+    /// previous values being kept.
+    pub(crate) fn merge(&mut self, newer: Self) {
+        self.data.val.extend(newer.data.val);
+        self.data.ts.extend(newer.data.ts);
+        self.tickets.extend(newer.tickets);
+        match (&mut self.change, newer.change) {
+            (Some(change), Some(newer_change)) => change.merge(newer_change),
+            (None, Some(newer_change)) => self.change = Some(newer_change),
+            (_, None) => {}
+        }
+        match (&mut self.prev, newer.prev) {
+            (Some(prev), Some(newer_prev)) => {
+                for (k, v) in newer_prev.val {
+                    prev.val.entry(k).or_insert(v);
+                }
+                for (k, v) in newer_prev.ts {
+                    prev.ts.entry(k).or_insert(v);
+                }
+            }
+            (None, Some(newer_prev)) => self.prev = Some(newer_prev),
+            (_, None) => {}
+        }
+    }
+}
+
+impl From<MongoDBData> for Publication {
+    fn from(data: MongoDBData) -> Self {
+        Self::new(data, None)
+    }
 }
 
 pub(crate) struct EnsureObject<T>(pub Option<T>);
@@ -123,6 +496,8 @@ mod tests {
     use super::*;
 
     mod update_event {
+        use crate::settings::testing::settings;
+
         use super::*;
 
         #[test]
@@ -148,16 +523,36 @@ mod tests {
                     Bson::DateTime(DateTime::from_millis(45000)),
                 ),
             ]);
-            let update_description = UpdateDescription { updated_fields };
+            let update_description = UpdateDescription {
+                updated_fields,
+                removed_fields: Vec::new(),
+            };
             let update_event = UpdateEvent {
+                resume_token: None,
                 ns,
                 document_key,
                 update_description,
+                full_document: None,
+                full_document_before_change: None,
+                cluster_time: None,
+                ticket: None,
             };
 
-            let (channel, data) = update_event.into_centrifugo();
+            let (
+                channel,
+                Publication {
+                    data,
+                    prev,
+                    version,
+                    ..
+                },
+            ) = update_event
+                .into_centrifugo(&mut Filter::default())
+                .unwrap();
 
             assert_eq!(channel, "testdb.testcoll:testid");
+            assert!(prev.is_none());
+            assert!(version.is_none());
 
             assert_eq!(data.val.len(), 2);
             assert_eq!(data.val["first"].to_string(), "true");
@@ -173,5 +568,341 @@ mod tests {
                 "1970-01-01 0:00:45.0 +00:00:00"
             );
         }
+
+        #[test]
+        fn into_centrifugo_filtered_out() {
+            let ns = Namespace {
+                db: "testdb".to_string(),
+                coll: "testcoll".to_string(),
+            };
+            let document_key = DocumentKey {
+                id: "testid".to_string(),
+            };
+            let updated_fields = HashMap::from([
+                ("val.first".to_string(), Bson::Boolean(true)),
+                (
+                    "ts.first".to_string(),
+                    Bson::DateTime(DateTime::from_millis(0)),
+                ),
+            ]);
+            let update_description = UpdateDescription {
+                updated_fields,
+                removed_fields: Vec::new(),
+            };
+            let update_event = UpdateEvent {
+                resume_token: None,
+                ns,
+                document_key,
+                update_description,
+                full_document: None,
+                full_document_before_change: None,
+                cluster_time: None,
+                ticket: None,
+            };
+            let settings = settings(&["--filter-exclude-fields", "first"]);
+            let mut filter = Filter::new(&settings.filter);
+
+            assert!(update_event.into_centrifugo(&mut filter).is_none());
+        }
+
+        #[test]
+        fn into_centrifugo_full_document() {
+            let ns = Namespace {
+                db: "testdb".to_string(),
+                coll: "testcoll".to_string(),
+            };
+            let document_key = DocumentKey {
+                id: "testid".to_string(),
+            };
+            let updated_fields = HashMap::from([("val.first".to_string(), Bson::Boolean(true))]);
+            let update_description = UpdateDescription {
+                updated_fields,
+                removed_fields: Vec::new(),
+            };
+            let mut full_document = MongoDBData::with_capacity(2);
+            full_document.insert_value("first".to_string(), Bson::Boolean(true));
+            full_document.insert_value("second".to_string(), Bson::Int32(5646));
+            let update_event = UpdateEvent {
+                resume_token: None,
+                ns,
+                document_key,
+                update_description,
+                full_document: Some(full_document),
+                full_document_before_change: None,
+                cluster_time: None,
+                ticket: None,
+            };
+
+            let (channel, Publication { data, prev, .. }) = update_event
+                .into_centrifugo(&mut Filter::default())
+                .unwrap();
+
+            assert_eq!(channel, "testdb.testcoll:testid");
+            assert!(prev.is_none());
+            assert_eq!(data.val.len(), 2);
+            assert_eq!(data.val["first"].to_string(), "true");
+            assert_eq!(data.val["second"].to_string(), "5646");
+        }
+
+        #[test]
+        fn into_centrifugo_resync() {
+            let ns = Namespace {
+                db: "testdb".to_string(),
+                coll: "testcoll".to_string(),
+            };
+            let mut data = MongoDBData::with_capacity(2);
+            data.insert_value("first".to_string(), Bson::Boolean(true));
+            data.insert_value("second".to_string(), Bson::Int32(5646));
+            let update_event = UpdateEvent::resync(ns, "testid".to_string(), data);
+            let settings =
+                crate::settings::testing::settings(&["--filter-exclude-fields", "second"]);
+            let mut filter = Filter::new(&settings.filter);
+
+            let (channel, publication) = update_event.into_centrifugo(&mut filter).unwrap();
+
+            assert_eq!(channel, "testdb.testcoll:testid");
+            assert!(publication.prev.is_none());
+            assert_eq!(publication.data.val.len(), 1);
+            assert_eq!(publication.data.val["first"].to_string(), "true");
+            assert_eq!(
+                serde_json::to_value(publication.change()).unwrap(),
+                serde_json::json!({
+                    "operation_type": "resync",
+                    "document_id": "testid",
+                    "updated_fields": [],
+                    "removed_fields": [],
+                    "cluster_time": null,
+                })
+            );
+        }
+
+        #[test]
+        fn into_centrifugo_pre_image() {
+            let ns = Namespace {
+                db: "testdb".to_string(),
+                coll: "testcoll".to_string(),
+            };
+            let document_key = DocumentKey {
+                id: "testid".to_string(),
+            };
+            let updated_fields = HashMap::from([
+                ("val.first".to_string(), Bson::Int32(2)),
+                ("val.new".to_string(), Bson::Int32(7)),
+                (
+                    "ts.first".to_string(),
+                    Bson::DateTime(DateTime::from_millis(45000)),
+                ),
+            ]);
+            let update_description = UpdateDescription {
+                updated_fields,
+                removed_fields: Vec::new(),
+            };
+            let mut before = MongoDBData::with_capacity(2);
+            before.insert_value("first".to_string(), Bson::Int32(1));
+            before.insert_value("unchanged".to_string(), Bson::Int32(3));
+            before.insert_timestamp("first".to_string(), DateTime::from_millis(0));
+            let update_event = UpdateEvent {
+                resume_token: None,
+                ns,
+                document_key,
+                update_description,
+                full_document: None,
+                full_document_before_change: Some(before),
+                cluster_time: None,
+                ticket: None,
+            };
+
+            let (_, Publication { data, prev, .. }) = update_event
+                .into_centrifugo(&mut Filter::default())
+                .unwrap();
+
+            assert_eq!(data.val.len(), 2);
+            let prev = prev.unwrap();
+            assert_eq!(prev.val.len(), 1);
+            assert_eq!(prev.val["first"].to_string(), "1");
+            assert_eq!(prev.ts.len(), 1);
+            assert_eq!(
+                prev.ts["first"].0.to_string(),
+                "1970-01-01 0:00:00.0 +00:00:00"
+            );
+        }
+    }
+
+    mod change_event {
+        use mongodb::bson::doc;
+
+        use super::*;
+
+        #[test]
+        fn update() {
+            let event = ChangeEvent::from_document(doc! {
+                "operationType": "update",
+                "ns": { "db": "testdb", "coll": "testcoll" },
+                "documentKey": { "_id": "testid" },
+                "updateDescription": { "updatedFields": { "val.first": 1 } },
+            })
+            .unwrap();
+            assert!(
+                matches!(event, Some(ChangeEvent::Update(event)) if event.document_id() == "testid")
+            );
+        }
+
+        #[test]
+        fn replace() {
+            let event = ChangeEvent::from_document(doc! {
+                "operationType": "replace",
+                "documentKey": { "_id": "testid" },
+                "fullDocument": { "_id": "testid", "val": { "first": 2 }, "ts": {} },
+            })
+            .unwrap();
+            let Some(ChangeEvent::Replace {
+                id,
+                data: Some(data),
+            }) = event
+            else {
+                panic!("not a replacement with data: {event:?}");
+            };
+            assert_eq!(id, "testid");
+            assert_eq!(data.val["first"].to_string(), "2");
+        }
+
+        #[test]
+        fn delete() {
+            let event = ChangeEvent::from_document(doc! {
+                "operationType": "delete",
+                "documentKey": { "_id": "testid" },
+            })
+            .unwrap();
+            assert!(matches!(event, Some(ChangeEvent::Delete { id }) if id == "testid"));
+        }
+
+        #[test]
+        fn other_operations() {
+            let invalidate = doc! { "operationType": "invalidate" };
+            assert!(matches!(
+                ChangeEvent::from_document(invalidate),
+                Ok(Some(ChangeEvent::Invalidate))
+            ));
+            let insert = doc! { "operationType": "insert", "documentKey": { "_id": "testid" } };
+            assert!(ChangeEvent::from_document(insert).unwrap().is_none());
+        }
+    }
+
+    mod mongodb_data {
+        use mongodb::bson::doc;
+
+        use super::*;
+
+        #[test]
+        fn lenient() {
+            let data = mongodb::bson::deserialize_from_document::<MongoDBData>(doc! {
+                "_id": "testid",
+                "val": { "first": 1 },
+                "ts": { "first": DateTime::from_millis(0), "invalid": "2024-05-01" },
+            })
+            .unwrap();
+            assert_eq!(data.val.len(), 1);
+            assert_eq!(data.ts.len(), 1);
+            assert!(data.ts.contains_key("first"));
+        }
+
+        #[test]
+        fn missing_or_invalid_fields() {
+            let data =
+                mongodb::bson::deserialize_from_document::<MongoDBData>(doc! { "val": 1 }).unwrap();
+            assert!(data.is_empty());
+        }
+
+        #[test]
+        fn full_document() {
+            let update_event = mongodb::bson::deserialize_from_document::<UpdateEvent>(doc! {
+                "ns": { "db": "testdb", "coll": "testcoll" },
+                "documentKey": { "_id": "testid" },
+                "updateDescription": { "updatedFields": { "val.first": 2 } },
+                "fullDocument": { "val": { "first": 2 }, "ts": { "first": 5 } },
+                "fullDocumentBeforeChange": { "ts": { "first": DateTime::from_millis(0) } },
+            })
+            .unwrap();
+
+            let (_, Publication { data, prev, .. }) = update_event
+                .into_centrifugo(&mut Filter::default())
+                .unwrap();
+
+            assert_eq!(data.val["first"].to_string(), "2");
+            assert!(data.ts.is_empty());
+            assert_eq!(prev.unwrap().ts.len(), 0);
+        }
+    }
+
+    mod publication {
+        use super::*;
+
+        #[test]
+        fn serialize_with_version() {
+            let mut data = MongoDBData::with_capacity(1);
+            data.insert_value("first".to_string(), Bson::Int32(9));
+            let mut publication = Publication::from(data);
+            publication.set_version(3);
+            let json = serde_json::to_string(&publication).unwrap();
+            assert_eq!(json, r#"{"val":{"first":9},"ts":{},"version":3}"#);
+        }
+
+        #[test]
+        fn merge() {
+            let mut older_data = MongoDBData::with_capacity(2);
+            older_data.insert_value("first".to_string(), Bson::Int32(1));
+            older_data.insert_value("second".to_string(), Bson::Int32(2));
+            let mut older_prev = MongoDBData::with_capacity(1);
+            older_prev.insert_value("first".to_string(), Bson::Int32(0));
+            let mut older = Publication::new(older_data, Some(older_prev));
+            let mut newer_data = MongoDBData::with_capacity(2);
+            newer_data.insert_value("first".to_string(), Bson::Int32(10));
+            newer_data.insert_value("third".to_string(), Bson::Int32(3));
+            let mut newer_prev = MongoDBData::with_capacity(2);
+            newer_prev.insert_value("first".to_string(), Bson::Int32(1));
+            newer_prev.insert_value("third".to_string(), Bson::Int32(30));
+            let newer = Publication::new(newer_data, Some(newer_prev));
+
+            older.merge(newer);
+
+            assert_eq!(older.data.val.len(), 3);
+            assert_eq!(older.data.val["first"].to_string(), "10");
+            assert_eq!(older.data.val["second"].to_string(), "2");
+            assert_eq!(older.data.val["third"].to_string(), "3");
+            let prev = older.prev.unwrap();
+            assert_eq!(prev.val["first"].to_string(), "0");
+            assert_eq!(prev.val["third"].to_string(), "30");
+        }
+
+        #[test]
+        fn merge_change() {
+            let change = |updated_fields: &[&str], cluster_time| Change {
+                operation_type: "update",
+                document_id: "testid".to_string(),
+                updated_fields: updated_fields.iter().map(|f| f.to_string()).collect(),
+                removed_fields: Vec::new(),
+                cluster_time: Some(cluster_time),
+            };
+            let mut older = Publication::from(MongoDBData::with_capacity(0))
+                .with_change(change(&["val.first", "val.second"], 1));
+            let newer = Publication::from(MongoDBData::with_capacity(0))
+                .with_change(change(&["val.first", "ts.first"], 2));
+
+            older.merge(newer);
+
+            let change = older.change().unwrap();
+            assert_eq!(
+                change.updated_fields,
+                ["val.first", "val.second", "ts.first"]
+            );
+            assert_eq!(change.cluster_time, Some(2));
+        }
+
+        #[test]
+        fn serialize_without_version() {
+            let publication = Publication::from(MongoDBData::with_capacity(0));
+            let json = serde_json::to_string(&publication).unwrap();
+            assert_eq!(json, r#"{"val":{},"ts":{}}"#);
+        }
     }
 }
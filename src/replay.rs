@@ -0,0 +1,127 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use mongodb::bson::Timestamp;
+use mongodb::change_stream::event::ResumeToken;
+use tracing::{info, instrument};
+
+use crate::centrifugo;
+use crate::db::{self, MongoDBCollection, ReplayStart};
+use crate::filter::Filter;
+use crate::model::PublicationMode;
+use crate::settings::Settings;
+
+/// Parses an RFC 3339 time into a cluster time.
+pub(crate) fn parse_timestamp(s: &str) -> Result<Timestamp, String> {
+    match s.parse::<ReplayStart>()? {
+        ReplayStart::Time(time) => Ok(time),
+        ReplayStart::ResumeToken(_) => Err(format!("`{s}` is not a RFC 3339 time")),
+    }
+}
+
+/// Returns the cluster time of the current second.
+fn now() -> Timestamp {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Timestamp {
+        time: u32::try_from(since_epoch.as_secs()).unwrap_or(u32::MAX),
+        increment: 0,
+    }
+}
+
+/// Outcome of a replay.
+pub(crate) struct ReplayOutcome {
+    pub(crate) replayed: usize,
+    pub(crate) failures: usize,
+    /// Resume token of the last replayed change.
+    pub(crate) resume_token: Option<ResumeToken>,
+}
+
+/// Republishes past changes, until the end time or, if none, the time the replay started.
+///
+/// Changes are read from a change stream separate from the live one, and go through a filter
+/// of their own, so that the live stream is not disturbed.
+#[instrument(name = "replay", skip_all)]
+pub(crate) async fn replay(
+    collection: &MongoDBCollection,
+    config: &db::Config,
+    publication_mode: PublicationMode,
+    settings: &Settings,
+    start: &ReplayStart,
+    end: Option<Timestamp>,
+) -> anyhow::Result<ReplayOutcome> {
+    let mut filter = Filter::new(&settings.filter);
+    let mut change_stream = collection
+        .replay_change_stream(config, publication_mode, start)
+        .await?;
+    info!(status = "started", ?start, ?end);
+
+    // Without an end time, changes are replayed until the time the replay started.
+    let end = end.unwrap_or_else(now);
+    let (mut replayed, mut failures) = (0, 0);
+    let mut resume_token = None;
+    loop {
+        let Some(update_event) = change_stream
+            .next_if_any()
+            .await
+            .context("error reading change stream")?
+        else {
+            // An empty batch only tells how far the change stream has read the oplog.
+            let caught_up = change_stream
+                .resume_token()
+                .as_ref()
+                .and_then(db::token_cluster_time)
+                .is_some_and(|cluster_time| cluster_time >= end);
+            if caught_up || !change_stream.is_alive() {
+                break;
+            }
+            continue;
+        };
+        let after_end = update_event
+            .cluster_time()
+            .is_some_and(|cluster_time| cluster_time > end);
+        if after_end {
+            break;
+        }
+        // The change stream's own token may be past the end, as it has read the next change.
+        if let Some(token) = update_event.resume_token() {
+            resume_token = Some(token.clone());
+        }
+        failures += centrifugo::publish_event(settings, &mut filter, update_event).await;
+        replayed += 1;
+    }
+
+    info!(status = "finished", replayed, failures);
+    Ok(ReplayOutcome {
+        replayed,
+        failures,
+        resume_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_timestamp {
+        use super::*;
+
+        #[test]
+        fn time() {
+            let timestamp = parse_timestamp("2023-01-13T08:30:00Z").unwrap();
+            assert_eq!(
+                timestamp,
+                Timestamp {
+                    time: 1673598600,
+                    increment: 0
+                }
+            );
+        }
+
+        #[test]
+        fn resume_token() {
+            assert!(parse_timestamp("8263C1192A000000012B0229296E04").is_err());
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::BTreeSet;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use clap::{Args, ValueEnum};
+use futures_util::StreamExt;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::centrifugo::{self, TagsUpdateChannel};
+use crate::db::{self, MongoDBCollection};
+use crate::model::UpdateEvent;
+use crate::settings::{Settings, SettingsReceiver};
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Interval of periodic resyncs, publishing the full data of documents (e.g. `1h`, resyncs
+    /// are disabled if not set)
+    #[arg(env, long, value_parser = humantime::parse_duration)]
+    resync_interval: Option<Duration>,
+
+    /// Maximum number of documents published per second during resyncs
+    #[arg(env, long, default_value = "100")]
+    resync_rate: NonZeroU32,
+
+    /// Documents published during resyncs: `all` documents of the collection, or those whose
+    /// channel has subscribers on any Centrifugo cluster (`subscribed`)
+    #[arg(env, long, value_enum, default_value_t)]
+    resync_scope: ResyncScope,
+}
+
+/// Documents published during resyncs.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum ResyncScope {
+    #[default]
+    All,
+    Subscribed,
+}
+
+/// Periodically publishes the full data of documents, so that clients which missed
+/// publications do not stay stale.
+struct Resync {
+    collection: MongoDBCollection,
+    mongodb_config: db::Config,
+    scope: ResyncScope,
+    rate: NonZeroU32,
+    settings_rx: SettingsReceiver,
+    tags_update_channel: TagsUpdateChannel,
+}
+
+impl Resync {
+    /// Publishes the full data of documents in scope, returning the number of published ones.
+    async fn run(&self) -> anyhow::Result<usize> {
+        let mut pace = time::interval(Duration::from_secs(1) / self.rate.get());
+        pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut published = 0;
+        match self.scope {
+            ResyncScope::All => {
+                let mut ids = self.collection.document_ids(&self.mongodb_config).await?;
+                while let Some(id) = ids.next().await {
+                    match id? {
+                        Ok(id) => {
+                            if self.resync(&mut pace, &id).await? {
+                                published += 1;
+                            }
+                        }
+                        Err(err) => error!(kind = "resync document", err = format!("{err:#}")),
+                    }
+                }
+            }
+            ResyncScope::Subscribed => {
+                let settings = self.settings_rx.borrow().clone();
+                let ids = subscribed_ids(&settings, &self.collection.namespace()).await;
+                for id in ids {
+                    if self.resync(&mut pace, &id).await? {
+                        published += 1;
+                    }
+                }
+            }
+        }
+        Ok(published)
+    }
+
+    /// Publishes the full data of a document, returning whether it was published.
+    ///
+    /// Errors reading the document are logged, only failures to send are returned.
+    async fn resync(&self, pace: &mut Interval, id: &str) -> anyhow::Result<bool> {
+        match self.collection.resync_event(&self.mongodb_config, id).await {
+            Ok(Some(event)) => {
+                self.publish(pace, event).await?;
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => {
+                error!(kind = "resync document", id, err = format!("{err:#}"));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Sends an event to the tags update channel, at the resync pace and once the channel is
+    /// at most half full, so that changes from the change stream always have room.
+    async fn publish(&self, pace: &mut Interval, event: UpdateEvent) -> anyhow::Result<()> {
+        pace.tick().await;
+        while !has_room(&self.tags_update_channel) {
+            pace.tick().await;
+        }
+        self.tags_update_channel
+            .send(event)
+            .await
+            .context("error sending to tags update channel")
+    }
+}
+
+/// Returns whether at least half of the channel capacity is available.
+fn has_room(tags_update_channel: &TagsUpdateChannel) -> bool {
+    tags_update_channel.capacity() >= tags_update_channel.max_capacity().div_ceil(2)
+}
+
+/// Returns identifiers of documents whose channel has subscribers on any Centrifugo cluster.
+async fn subscribed_ids(settings: &Settings, namespace: &str) -> BTreeSet<String> {
+    let prefix = format!("{namespace}:");
+    let (channels, _) = centrifugo::active_channels(settings, &format!("{prefix}*")).await;
+    channels
+        .into_iter()
+        .filter_map(|channel| channel.strip_prefix(&prefix).map(str::to_string))
+        .collect()
+}
+
+pub(crate) fn handle_resync(
+    config: &Config,
+    collection: MongoDBCollection,
+    mongodb_config: &db::Config,
+    settings_rx: SettingsReceiver,
+    tags_update_channel: TagsUpdateChannel,
+    shutdown_token: CancellationToken,
+) -> JoinHandle<()> {
+    let interval = config.resync_interval;
+    let resync = Resync {
+        collection,
+        mongodb_config: mongodb_config.clone(),
+        scope: config.resync_scope,
+        rate: config.resync_rate,
+        settings_rx,
+        tags_update_channel,
+    };
+
+    tokio::spawn(
+        async move {
+            let Some(interval) = interval else {
+                info!(status = "disabled");
+                return;
+            };
+            info!(status = "started");
+
+            let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = shutdown_token.cancelled() => break,
+                }
+                tokio::select! {
+                    outcome = resync.run() => match outcome {
+                        Ok(published) => info!(msg = "resync done", published),
+                        Err(err) => error!(kind = "resync", err = format!("{err:#}")),
+                    },
+                    _ = shutdown_token.cancelled() => break,
+                }
+            }
+
+            info!(status = "terminating");
+        }
+        .instrument(info_span!("resync_handler")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod has_room {
+        use tokio::sync::mpsc;
+
+        use super::*;
+
+        fn update_event() -> UpdateEvent {
+            serde_json::from_value(serde_json::json!({
+                "ns": { "db": "db", "coll": "coll" },
+                "documentKey": { "_id": "doc" },
+                "updateDescription": { "updatedFields": {} },
+            }))
+            .unwrap()
+        }
+
+        #[test]
+        fn half_full() {
+            let (tx, _rx) = mpsc::channel(4);
+            assert!(has_room(&tx));
+            tx.try_send(update_event()).unwrap();
+            tx.try_send(update_event()).unwrap();
+            assert!(has_room(&tx));
+            tx.try_send(update_event()).unwrap();
+            assert!(!has_room(&tx));
+        }
+
+        #[test]
+        fn single_slot() {
+            let (tx, _rx) = mpsc::channel(1);
+            assert!(has_room(&tx));
+            tx.try_send(update_event()).unwrap();
+            assert!(!has_room(&tx));
+        }
+    }
+
+    mod subscribed_ids {
+        use mockito::Server;
+
+        use crate::settings::testing::settings;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn multiple_clusters() {
+            let mut first = Server::new_async().await;
+            let _first_mock = first
+                .mock("POST", "/api/channels")
+                .match_body(r#"{"pattern":"db.coll:*"}"#)
+                .with_body(
+                    r#"{"result":{"channels":{
+                        "db.coll:first":{"num_clients":1},
+                        "db.coll:second":{"num_clients":2}
+                    }}}"#,
+                )
+                .create_async()
+                .await;
+            let mut second = Server::new_async().await;
+            let _second_mock = second
+                .mock("POST", "/api/channels")
+                .with_body(
+                    r#"{"result":{"channels":{
+                        "db.coll:second":{"num_clients":1},
+                        "db.coll:third":{"num_clients":1}
+                    }}}"#,
+                )
+                .create_async()
+                .await;
+            let unreachable = Server::new_async().await;
+            let settings = settings(&[
+                "--centrifugo-url",
+                &first.url(),
+                "--centrifugo-cluster",
+                &format!("name=second,url={},api_key=otherkey", second.url()),
+                "--centrifugo-cluster",
+                &format!("name=third,url={},api_key=otherkey", unreachable.url()),
+            ]);
+
+            let ids = subscribed_ids(&settings, "db.coll").await;
+
+            assert_eq!(
+                ids.iter().map(String::as_str).collect::<Vec<_>>(),
+                ["first", "second", "third"]
+            );
+        }
+    }
+}
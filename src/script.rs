@@ -0,0 +1,287 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use clap::Args;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{AST, Dynamic, Engine, Scope};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, instrument};
+
+/// Maximum number of operations for a script function call, guarding against infinite loops.
+///
+/// Scripts run synchronously on async tasks (the tags update loop and HTTP handlers), so a call
+/// must stay short to not hold up other publications and requests.
+const MAX_OPERATIONS: u64 = 10_000;
+
+const PUBLISH_FN: &str = "publish";
+const SUBSCRIBE_FN: &str = "subscribe";
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Path of a Rhai script transforming publications and initial data
+    #[arg(env, long)]
+    transform_script: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct ScriptPublication {
+    channel: Option<String>,
+    data: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptPublications {
+    One(ScriptPublication),
+    Many(Vec<ScriptPublication>),
+}
+
+/// Payload transformation by a user-supplied script.
+pub(crate) struct Transform {
+    engine: Engine,
+    ast: AST,
+    /// Number of parameters of the `publish` function, if defined with 2 or 3.
+    publish_fn_params: Option<usize>,
+    has_subscribe_fn: bool,
+}
+
+impl Transform {
+    #[instrument(name = "transform_script_load", skip_all)]
+    pub(crate) fn load(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(path) = &config.transform_script else {
+            return Ok(None);
+        };
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("error reading transform script {}", path.display()))?;
+        let transform = Self::compile(&script)
+            .with_context(|| format!("error compiling transform script {}", path.display()))?;
+        info!(status = "success", path = %path.display());
+        Ok(Some(transform))
+    }
+
+    pub(crate) fn compile(script: &str) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(script)?;
+        let fn_params = |name, params: &[usize]| {
+            ast.iter_functions()
+                .filter(|f| f.name == name && params.contains(&f.params.len()))
+                .map(|f| f.params.len())
+                .max()
+        };
+        let publish_fn_params = fn_params(PUBLISH_FN, &[2, 3]);
+        let has_subscribe_fn = fn_params(SUBSCRIBE_FN, &[2]).is_some();
+        Ok(Self {
+            engine,
+            ast,
+            publish_fn_params,
+            has_subscribe_fn,
+        })
+    }
+
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Value, String> {
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+            .map_err(|err| err.to_string())?;
+        from_dynamic(&result).map_err(|err| err.to_string())
+    }
+
+    /// Calls the `publish(channel, data)` or `publish(channel, data, event)` script function,
+    /// which returns either a map with `data` and optional `channel` fields, an array of such
+    /// maps, or `()` to skip publishing.
+    pub(crate) fn publications(
+        &self,
+        channel: String,
+        data: &impl Serialize,
+        event: &impl Serialize,
+    ) -> Result<Vec<(String, Value)>, String> {
+        let Some(params) = self.publish_fn_params else {
+            let data = serde_json::to_value(data).map_err(|err| err.to_string())?;
+            return Ok(vec![(channel, data)]);
+        };
+        let data = to_dynamic(data).map_err(|err| err.to_string())?;
+        let result = if params == 3 {
+            let event = to_dynamic(event).map_err(|err| err.to_string())?;
+            self.call(PUBLISH_FN, (channel.clone(), data, event))?
+        } else {
+            self.call(PUBLISH_FN, (channel.clone(), data))?
+        };
+        if result.is_null() {
+            return Ok(vec![]);
+        }
+        let publications = match serde_json::from_value(result) {
+            Ok(ScriptPublications::One(publication)) => vec![publication],
+            Ok(ScriptPublications::Many(publications)) => publications,
+            Err(err) => return Err(format!("bad return value of `{PUBLISH_FN}`: {err}")),
+        };
+        Ok(publications
+            .into_iter()
+            .map(|publication| {
+                let channel = publication.channel.unwrap_or_else(|| channel.clone());
+                (channel, publication.data)
+            })
+            .collect())
+    }
+
+    /// Calls the `subscribe(channel, data)` script function, which returns the initial data for
+    /// the subscription.
+    pub(crate) fn initial_data(
+        &self,
+        channel: &str,
+        data: &impl Serialize,
+    ) -> Result<Value, String> {
+        if !self.has_subscribe_fn {
+            return serde_json::to_value(data).map_err(|err| err.to_string());
+        }
+        let data = to_dynamic(data).map_err(|err| err.to_string())?;
+        self.call(SUBSCRIBE_FN, (channel.to_string(), data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn compile_error() {
+        assert!(Transform::compile("fn publish(").is_err());
+    }
+
+    mod publications {
+        use super::*;
+
+        #[test]
+        fn without_function() {
+            let transform = Transform::compile("").unwrap();
+            let publications = transform
+                .publications("chan".to_string(), &json!({"val": {"a": 1}}), &())
+                .unwrap();
+            assert_eq!(
+                publications,
+                [("chan".to_string(), json!({"val": {"a": 1}}))]
+            );
+        }
+
+        #[test]
+        fn skip() {
+            let transform = Transform::compile("fn publish(channel, data) { () }").unwrap();
+            let publications = transform
+                .publications("chan".to_string(), &json!({}), &())
+                .unwrap();
+            assert!(publications.is_empty());
+        }
+
+        #[test]
+        fn single() {
+            let script = r#"
+                fn publish(channel, data) {
+                    #{ data: #{ value: data.val.a * 2 } }
+                }
+            "#;
+            let transform = Transform::compile(script).unwrap();
+            let publications = transform
+                .publications("chan".to_string(), &json!({"val": {"a": 21}}), &())
+                .unwrap();
+            assert_eq!(publications, [("chan".to_string(), json!({"value": 42}))]);
+        }
+
+        #[test]
+        fn many() {
+            let script = r#"
+                fn publish(channel, data) {
+                    [
+                        #{ channel: channel + "-a", data: data.val.a },
+                        #{ channel: channel + "-b", data: data.val.b },
+                    ]
+                }
+            "#;
+            let transform = Transform::compile(script).unwrap();
+            let publications = transform
+                .publications("chan".to_string(), &json!({"val": {"a": 1, "b": "x"}}), &())
+                .unwrap();
+            assert_eq!(
+                publications,
+                [
+                    ("chan-a".to_string(), json!(1)),
+                    ("chan-b".to_string(), json!("x"))
+                ]
+            );
+        }
+
+        #[test]
+        fn with_event() {
+            let script = r#"
+                fn publish(channel, data, event) {
+                    #{ data: #{ id: event.document_id, fields: event.updated_fields } }
+                }
+            "#;
+            let transform = Transform::compile(script).unwrap();
+            let publications = transform
+                .publications(
+                    "chan".to_string(),
+                    &json!({}),
+                    &json!({"document_id": "doc", "updated_fields": ["val.a"]}),
+                )
+                .unwrap();
+            assert_eq!(
+                publications,
+                [(
+                    "chan".to_string(),
+                    json!({"id": "doc", "fields": ["val.a"]})
+                )]
+            );
+        }
+
+        #[test]
+        fn bad_return_value() {
+            let transform = Transform::compile("fn publish(channel, data) { 42 }").unwrap();
+            let result = transform.publications("chan".to_string(), &json!({}), &());
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn runtime_error() {
+            let transform =
+                Transform::compile("fn publish(channel, data) { throw \"oops\" }").unwrap();
+            let result = transform.publications("chan".to_string(), &json!({}), &());
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn infinite_loop() {
+            let transform = Transform::compile("fn publish(channel, data) { loop {} }").unwrap();
+            let result = transform.publications("chan".to_string(), &json!({}), &());
+            assert!(result.is_err());
+        }
+    }
+
+    mod initial_data {
+        use super::*;
+
+        #[test]
+        fn without_function() {
+            let transform = Transform::compile("").unwrap();
+            let data = transform.initial_data("chan", &json!({"a": 1})).unwrap();
+            assert_eq!(data, json!({"a": 1}));
+        }
+
+        #[test]
+        fn transformed() {
+            let script = r#"
+                fn subscribe(channel, data) {
+                    #{ channel: channel, count: data.len() }
+                }
+            "#;
+            let transform = Transform::compile(script).unwrap();
+            let data = transform
+                .initial_data("chan", &json!({"a": 1, "b": 2}))
+                .unwrap();
+            assert_eq!(data, json!({"channel": "chan", "count": 2}));
+        }
+    }
+}
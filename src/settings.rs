@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::centrifugo;
+use crate::filter;
+use crate::script::{self, Transform};
+use crate::throttle;
+
+pub(crate) type SettingsReceiver = watch::Receiver<Arc<Settings>>;
+
+/// Settings which can be reloaded without restarting the service.
+pub(crate) struct Settings {
+    pub(crate) centrifugo_clients: Vec<centrifugo::Client>,
+    pub(crate) centrifugo_health_cache_ttl: Duration,
+    pub(crate) filter: filter::Config,
+    pub(crate) throttle: throttle::Config,
+    pub(crate) transform: Option<Arc<Transform>>,
+}
+
+impl Settings {
+    pub(crate) fn new(
+        centrifugo: &centrifugo::Config,
+        filter: &filter::Config,
+        throttle: &throttle::Config,
+        script: &script::Config,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            centrifugo_clients: centrifugo::clients(centrifugo)?,
+            centrifugo_health_cache_ttl: centrifugo.health_cache_ttl(),
+            filter: filter.clone(),
+            throttle: throttle.clone(),
+            transform: Transform::load(script)?.map(Arc::new),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Args {
+        #[command(flatten)]
+        centrifugo: centrifugo::Config,
+
+        #[command(flatten)]
+        filter: filter::Config,
+
+        #[command(flatten)]
+        throttle: throttle::Config,
+
+        #[command(flatten)]
+        script: script::Config,
+    }
+
+    /// Returns settings parsed from given command line arguments, or the error building them.
+    ///
+    /// The Centrifugo API key defaults to `somekey`.
+    pub(crate) fn try_settings(args: &[&str]) -> anyhow::Result<Settings> {
+        let has_api_key = args
+            .iter()
+            .any(|arg| arg.starts_with("--centrifugo-api-key"));
+        let default_api_key = if has_api_key {
+            &[][..]
+        } else {
+            &["--centrifugo-api-key", "somekey"][..]
+        };
+        let args = Args::parse_from(std::iter::once(&"test").chain(default_api_key).chain(args));
+        Settings::new(&args.centrifugo, &args.filter, &args.throttle, &args.script)
+    }
+
+    /// Returns settings parsed from given command line arguments.
+    pub(crate) fn settings(args: &[&str]) -> Settings {
+        try_settings(args).unwrap()
+    }
+
+    /// Returns a settings receiver, with settings parsed from given command line arguments.
+    pub(crate) fn settings_receiver(args: &[&str]) -> SettingsReceiver {
+        watch::channel(Arc::new(settings(args))).1
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Args;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span};
+
+use crate::centrifugo;
+use crate::settings::SettingsReceiver;
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Interval of refreshes of the channels having subscribers, from Centrifugo `channels` API
+    /// (e.g. `30s`); when set, changes of documents nobody subscribed to are not published
+    #[arg(env, long, value_parser = humantime::parse_duration)]
+    subscriptions_refresh_interval: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Tracking {
+    /// Channels having subscribers, or `None` if unknown.
+    active: Option<HashSet<String>>,
+    /// Channels subscribed to while a refresh is in progress, or `None` if not refreshing.
+    subscribed_during_refresh: Option<HashSet<String>>,
+    /// Number of publications skipped for lack of subscribers.
+    skipped: u64,
+    /// Number of successful refreshes.
+    refreshes: u64,
+}
+
+/// Set of channels having subscribers, shared between the subscriptions handler, the subscribe
+/// proxy and the tags update handler.
+///
+/// As long as the set is unknown (tracking disabled, not refreshed yet or last refresh failed),
+/// all channels are considered active.
+#[derive(Clone, Default)]
+pub(crate) struct ActiveChannels(Arc<Mutex<Tracking>>);
+
+impl ActiveChannels {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracking> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Returns whether the channel has subscribers, or may have.
+    pub(crate) fn is_active(&self, channel: &str) -> bool {
+        self.lock()
+            .active
+            .as_ref()
+            .is_none_or(|active| active.contains(channel))
+    }
+
+    /// Records a subscription to the channel.
+    pub(crate) fn insert(&self, channel: &str) {
+        let mut tracking = self.lock();
+        if let Some(active) = &mut tracking.active {
+            active.insert(channel.to_string());
+        }
+        if let Some(subscribed) = &mut tracking.subscribed_during_refresh {
+            subscribed.insert(channel.to_string());
+        }
+    }
+
+    /// Returns the number of channels having subscribers, if known.
+    pub(crate) fn count(&self) -> Option<usize> {
+        self.lock().active.as_ref().map(HashSet::len)
+    }
+
+    /// Records a publication skipped for lack of subscribers.
+    pub(crate) fn record_skipped(&self) {
+        self.lock().skipped += 1;
+    }
+
+    /// Returns the number of publications skipped for lack of subscribers.
+    pub(crate) fn skipped(&self) -> u64 {
+        self.lock().skipped
+    }
+
+    /// Returns the number of successful refreshes, changing whenever channels may have lost
+    /// their subscribers.
+    pub(crate) fn refreshes(&self) -> u64 {
+        self.lock().refreshes
+    }
+
+    /// Returns a set of known active channels.
+    #[cfg(test)]
+    pub(crate) fn known(channels: &[&str]) -> Self {
+        let active_channels = Self::default();
+        active_channels.refresh(channels);
+        active_channels
+    }
+
+    /// Replaces the set with given channels, as a successful refresh.
+    #[cfg(test)]
+    pub(crate) fn refresh(&self, channels: &[&str]) {
+        self.finish_refresh(Some(channels.iter().map(|c| c.to_string()).collect()));
+    }
+
+    fn begin_refresh(&self) {
+        self.lock().subscribed_during_refresh = Some(HashSet::new());
+    }
+
+    /// Replaces the set with refreshed channels, keeping channels subscribed to meanwhile.
+    fn finish_refresh(&self, refreshed: Option<HashSet<String>>) {
+        let mut tracking = self.lock();
+        let subscribed = tracking.subscribed_during_refresh.take();
+        if refreshed.is_some() {
+            tracking.refreshes += 1;
+        }
+        tracking.active = refreshed.map(|mut active| {
+            active.extend(subscribed.into_iter().flatten());
+            active
+        });
+    }
+}
+
+pub(crate) fn handle_subscriptions(
+    config: &Config,
+    active_channels: ActiveChannels,
+    namespace: String,
+    settings_rx: SettingsReceiver,
+    shutdown_token: CancellationToken,
+) -> JoinHandle<()> {
+    let interval = config.subscriptions_refresh_interval;
+    let pattern = format!("{namespace}:*");
+
+    tokio::spawn(
+        async move {
+            let Some(interval) = interval else {
+                info!(status = "disabled");
+                return;
+            };
+            info!(status = "started");
+
+            let mut ticker = time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = shutdown_token.cancelled() => break,
+                }
+                let settings = settings_rx.borrow().clone();
+                active_channels.begin_refresh();
+                let (channels, failed) = centrifugo::active_channels(&settings, &pattern).await;
+                if failed.is_empty() {
+                    info!(msg = "active channels refreshed", count = channels.len());
+                    active_channels.finish_refresh(Some(channels));
+                } else {
+                    error!(
+                        kind = "refreshing active channels",
+                        clusters = failed.join(", "),
+                        reaction = "publishing on all channels"
+                    );
+                    active_channels.finish_refresh(None);
+                }
+            }
+
+            info!(status = "terminating");
+        }
+        .instrument(info_span!("subscriptions_handler")),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod active_channels {
+        use super::*;
+
+        #[test]
+        fn unknown() {
+            let active_channels = ActiveChannels::default();
+            active_channels.insert("ns:first");
+            assert!(active_channels.is_active("ns:other"));
+            assert_eq!(active_channels.count(), None);
+        }
+
+        #[test]
+        fn refreshed() {
+            let active_channels = ActiveChannels::default();
+            active_channels.begin_refresh();
+            active_channels.finish_refresh(Some(HashSet::from(["ns:first".to_string()])));
+            assert!(active_channels.is_active("ns:first"));
+            assert!(!active_channels.is_active("ns:second"));
+            active_channels.insert("ns:second");
+            assert!(active_channels.is_active("ns:second"));
+            assert_eq!(active_channels.count(), Some(2));
+        }
+
+        #[test]
+        fn subscribed_during_refresh() {
+            let active_channels = ActiveChannels::default();
+            active_channels.begin_refresh();
+            active_channels.insert("ns:second");
+            active_channels.finish_refresh(Some(HashSet::from(["ns:first".to_string()])));
+            assert!(active_channels.is_active("ns:second"));
+            assert_eq!(active_channels.count(), Some(2));
+        }
+
+        #[test]
+        fn failed_refresh() {
+            let active_channels = ActiveChannels::default();
+            active_channels.begin_refresh();
+            active_channels.finish_refresh(Some(HashSet::from(["ns:first".to_string()])));
+            active_channels.begin_refresh();
+            active_channels.finish_refresh(None);
+            assert!(active_channels.is_active("ns:second"));
+            assert_eq!(active_channels.count(), None);
+        }
+    }
+
+    mod handle_subscriptions {
+        use mockito::Server;
+
+        use crate::settings::testing::settings_receiver;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn refresh() {
+            let mut server = Server::new_async().await;
+            let mock = server
+                .mock("POST", "/api/channels")
+                .match_body(r#"{"pattern":"db.coll:*"}"#)
+                .with_body(r#"{"result":{"channels":{"db.coll:first":{"num_clients":1}}}}"#)
+                .create_async()
+                .await;
+            let config = Config {
+                subscriptions_refresh_interval: Some(Duration::from_secs(3600)),
+            };
+            let active_channels = ActiveChannels::default();
+            let shutdown_token = CancellationToken::new();
+            let task = handle_subscriptions(
+                &config,
+                active_channels.clone(),
+                "db.coll".to_string(),
+                settings_receiver(&["--centrifugo-url", &server.url()]),
+                shutdown_token.clone(),
+            );
+
+            time::timeout(Duration::from_secs(5), async {
+                while active_channels.count().is_none() {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+            assert!(active_channels.is_active("db.coll:first"));
+            assert!(!active_channels.is_active("db.coll:second"));
+            mock.assert_async().await;
+            shutdown_token.cancel();
+            task.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Args;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::model::Publication;
+
+#[derive(Args, Clone)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// Throttling interval for publications on a channel (disabled if not set)
+    #[arg(env, long, value_parser = humantime::parse_duration)]
+    throttle_interval: Option<Duration>,
+
+    /// Maximum number of publications on a channel per throttling interval
+    #[arg(env, long, default_value = "1")]
+    throttle_max_publications: NonZeroU32,
+
+    /// Maximum number of publications per throttling interval for channels matching a pattern,
+    /// overriding the global one (comma-separated PATTERN=MAX, `*` matching any characters)
+    #[arg(env, long, value_delimiter = ',')]
+    throttle_rule: Vec<ThrottleRule>,
+}
+
+#[derive(Clone, Debug)]
+struct ThrottleRule {
+    pattern: String,
+    max_publications: NonZeroU32,
+}
+
+impl FromStr for ThrottleRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, max_publications) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("missing `=` in `{s}`"))?;
+        if pattern.is_empty() {
+            return Err(format!("missing channel pattern in `{s}`"));
+        }
+        let max_publications = max_publications
+            .parse()
+            .map_err(|err| format!("invalid maximum number of publications in `{s}`: {err}"))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            max_publications,
+        })
+    }
+}
+
+/// Returns whether the text matches the pattern, where `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[derive(Default)]
+struct Window {
+    count: u32,
+    pending: Option<Publication>,
+}
+
+/// Per-channel publications throttling, with fixed windows.
+///
+/// Publications exceeding the maximum in a window are merged into a pending one (latest values
+/// win), which is published at the beginning of the next window.
+pub(crate) struct Throttle {
+    interval: Option<Interval>,
+    max_publications: NonZeroU32,
+    rules: Vec<ThrottleRule>,
+    windows: HashMap<String, Window>,
+}
+
+impl Throttle {
+    pub(crate) fn new(config: &Config) -> Self {
+        let interval = config.throttle_interval.map(|period| {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self {
+            interval,
+            max_publications: config.throttle_max_publications,
+            rules: config.throttle_rule.clone(),
+            windows: HashMap::new(),
+        }
+    }
+
+    fn max_publications(&self, channel: &str) -> u32 {
+        self.rules
+            .iter()
+            .find(|rule| matches_pattern(&rule.pattern, channel))
+            .map_or(self.max_publications, |rule| rule.max_publications)
+            .get()
+    }
+
+    /// Returns the publication if it can be published right away, or keeps it pending.
+    pub(crate) fn admit(
+        &mut self,
+        channel: String,
+        publication: Publication,
+    ) -> Option<(String, Publication)> {
+        if self.interval.is_none() {
+            return Some((channel, publication));
+        }
+        let max_publications = self.max_publications(&channel);
+        let window = self.windows.entry(channel.clone()).or_default();
+        if window.count < max_publications {
+            window.count += 1;
+            return Some((channel, publication));
+        }
+        match &mut window.pending {
+            Some(pending) => pending.merge(publication),
+            None => window.pending = Some(publication),
+        }
+        None
+    }
+
+    /// Waits for the end of the current window (forever if throttling is disabled).
+    pub(crate) async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Starts a new window, returning pending publications, which count in the new window.
+    pub(crate) fn flush(&mut self) -> Vec<(String, Publication)> {
+        let mut flushed = Vec::new();
+        self.windows
+            .retain(|channel, window| match window.pending.take() {
+                Some(pending) => {
+                    flushed.push((channel.clone(), pending));
+                    window.count = 1;
+                    true
+                }
+                None => false,
+            });
+        flushed
+    }
+
+    /// Returns all pending publications, for a last flush.
+    pub(crate) fn drain(&mut self) -> Vec<(String, Publication)> {
+        self.windows
+            .drain()
+            .filter_map(|(channel, window)| window.pending.map(|pending| (channel, pending)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_matching() {
+        assert!(matches_pattern("abc", "abc"));
+        assert!(!matches_pattern("abc", "abcd"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("db.coll:plc*", "db.coll:plc42"));
+        assert!(!matches_pattern("db.coll:plc*", "db.coll:other"));
+        assert!(matches_pattern("*:plc*", "db.coll:plc42"));
+        assert!(matches_pattern("*a*b", "xxaxxb"));
+        assert!(!matches_pattern("*a*b", "xxbxxa"));
+        assert!(!matches_pattern("a*a", "a"));
+    }
+
+    mod throttle_rule {
+        use super::*;
+
+        #[test]
+        fn invalid() {
+            assert!("pattern".parse::<ThrottleRule>().is_err());
+            assert!("=1".parse::<ThrottleRule>().is_err());
+            assert!("pattern=0".parse::<ThrottleRule>().is_err());
+            assert!("pattern=abc".parse::<ThrottleRule>().is_err());
+        }
+
+        #[test]
+        fn success() {
+            let rule = "*:plc*=5".parse::<ThrottleRule>().unwrap();
+            assert_eq!(rule.pattern, "*:plc*");
+            assert_eq!(rule.max_publications.get(), 5);
+        }
+    }
+
+    mod throttle {
+        use mongodb::bson::Bson;
+
+        use crate::model::MongoDBData;
+
+        use super::*;
+
+        fn publication(value: i32) -> Publication {
+            let mut data = MongoDBData::with_capacity(1);
+            data.insert_value("first".to_string(), Bson::Int32(value));
+            data.insert_value(format!("field{value}"), Bson::Int32(value));
+            data.into()
+        }
+
+        fn throttle(max_publications: u32, rules: Vec<ThrottleRule>) -> Throttle {
+            Throttle {
+                interval: Some(tokio::time::interval(Duration::from_secs(1))),
+                max_publications: NonZeroU32::new(max_publications).unwrap(),
+                rules,
+                windows: HashMap::new(),
+            }
+        }
+
+        #[tokio::test]
+        async fn disabled() {
+            let mut throttle = Throttle {
+                interval: None,
+                max_publications: NonZeroU32::MIN,
+                rules: vec![],
+                windows: HashMap::new(),
+            };
+            for value in 0..5 {
+                assert!(throttle.admit("chan".into(), publication(value)).is_some());
+            }
+            assert!(throttle.flush().is_empty());
+        }
+
+        #[tokio::test]
+        async fn latest_value_wins() {
+            let mut throttle = throttle(2, vec![]);
+            assert!(throttle.admit("chan".into(), publication(1)).is_some());
+            assert!(throttle.admit("chan".into(), publication(2)).is_some());
+            assert!(throttle.admit("chan".into(), publication(3)).is_none());
+            assert!(throttle.admit("chan".into(), publication(4)).is_none());
+            assert!(throttle.admit("other".into(), publication(5)).is_some());
+
+            let flushed = throttle.flush();
+            assert_eq!(flushed.len(), 1);
+            let (channel, pending) = &flushed[0];
+            assert_eq!(channel, "chan");
+            let json = serde_json::to_value(pending).unwrap();
+            assert_eq!(json["val"]["first"], 4);
+            assert_eq!(json["val"]["field3"], 3);
+            assert_eq!(json["val"]["field4"], 4);
+
+            // Flushed publication counts in the new window.
+            assert!(throttle.admit("chan".into(), publication(6)).is_some());
+            assert!(throttle.admit("chan".into(), publication(7)).is_none());
+            assert_eq!(throttle.drain().len(), 1);
+            assert!(throttle.flush().is_empty());
+        }
+
+        #[tokio::test]
+        async fn rule_override() {
+            let rules = vec!["*:fast*=3".parse().unwrap()];
+            let mut throttle = throttle(1, rules);
+            for _ in 0..3 {
+                assert!(throttle.admit("ns:fast1".into(), publication(1)).is_some());
+            }
+            assert!(throttle.admit("ns:fast1".into(), publication(1)).is_none());
+            assert!(throttle.admit("ns:slow1".into(), publication(1)).is_some());
+            assert!(throttle.admit("ns:slow1".into(), publication(1)).is_none());
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, anyhow};
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use clap::Args;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_util::either::Either;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, info_span, instrument};
+
+use centrifugo_change_stream::CommonArgs;
+
+use crate::secret::modified;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Args)]
+#[group(skip)]
+pub(crate) struct Config {
+    /// PEM file of certificate authorities for verifying client certificates on the subscribe
+    /// proxy endpoint
+    #[arg(env, long)]
+    listen_tls_client_ca_file: Option<PathBuf>,
+}
+
+impl Config {
+    pub(crate) fn client_certificate_required(&self) -> bool {
+        self.listen_tls_client_ca_file.is_some()
+    }
+}
+
+fn load_certified_key(
+    certificate_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> anyhow::Result<CertifiedKey> {
+    let certificates = CertificateDer::pem_file_iter(certificate_path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| {
+            format!(
+                "error reading certificates from {}",
+                certificate_path.display()
+            )
+        })?;
+    if certificates.is_empty() {
+        return Err(anyhow!("no certificate in {}", certificate_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("error reading private key from {}", key_path.display()))?;
+    CertifiedKey::from_der(certificates, key, provider).context("invalid certificate or key")
+}
+
+/// Certificate and key files of the HTTP API, reloaded when they are modified.
+pub(crate) struct CertificateReloader {
+    certificate_path: PathBuf,
+    key_path: PathBuf,
+    provider: Arc<CryptoProvider>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    certified_key: Arc<RwLock<Arc<CertifiedKey>>>,
+}
+
+impl CertificateReloader {
+    fn new(
+        certificate_path: &Path,
+        key_path: &Path,
+        provider: Arc<CryptoProvider>,
+    ) -> anyhow::Result<Self> {
+        let modified = (modified(certificate_path)?, modified(key_path)?);
+        let certified_key = load_certified_key(certificate_path, key_path, &provider)?;
+        Ok(Self {
+            certificate_path: certificate_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            provider,
+            modified,
+            certified_key: Arc::new(RwLock::new(Arc::new(certified_key))),
+        })
+    }
+
+    fn resolver(&self) -> ReloadingResolver {
+        ReloadingResolver(Arc::clone(&self.certified_key))
+    }
+
+    #[instrument(name = "tls_certificate_reload", skip_all)]
+    fn reload_if_modified(&mut self) {
+        let modified = match (modified(&self.certificate_path), modified(&self.key_path)) {
+            (Ok(certificate), Ok(key)) => (certificate, key),
+            (Err(err), _) | (_, Err(err)) => {
+                error!(kind = "reading file metadata", %err);
+                return;
+            }
+        };
+        if modified == self.modified && modified.0.is_some() && modified.1.is_some() {
+            return;
+        }
+        match load_certified_key(&self.certificate_path, &self.key_path, &self.provider) {
+            Ok(certified_key) => {
+                self.modified = modified;
+                *self
+                    .certified_key
+                    .write()
+                    .unwrap_or_else(|err| err.into_inner()) = Arc::new(certified_key);
+                info!(status = "success");
+            }
+            Err(err) => {
+                error!(kind = "loading certificate", err = format!("{err:#}"));
+            }
+        }
+    }
+}
+
+/// Server certificate resolver, giving the certificate last loaded by the reloader.
+#[derive(Debug)]
+struct ReloadingResolver(Arc<RwLock<Arc<CertifiedKey>>>);
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified_key = self.0.read().unwrap_or_else(|err| err.into_inner());
+        Some(Arc::clone(&certified_key))
+    }
+}
+
+/// Checks periodically whether the certificate or key files were modified, to reload them.
+pub(crate) fn handle_certificate_reload(
+    reloader: Option<CertificateReloader>,
+    shutdown_token: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(
+        async move {
+            let Some(mut reloader) = reloader else {
+                info!(status = "disabled");
+                return;
+            };
+            info!(status = "started");
+
+            let mut ticker = time::interval_at(
+                Instant::now() + CERTIFICATE_CHECK_INTERVAL,
+                CERTIFICATE_CHECK_INTERVAL,
+            );
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    () = shutdown_token.cancelled() => break,
+                }
+                reloader.reload_if_modified();
+            }
+
+            info!(status = "terminating");
+        }
+        .instrument(info_span!("tls_certificate_handler")),
+    )
+}
+
+/// Builds the TLS acceptor of the HTTP API, with the reloader of its certificate, if TLS is
+/// enabled.
+pub(crate) fn acceptor(
+    common: &CommonArgs,
+    config: &Config,
+) -> anyhow::Result<Option<(TlsAcceptor, CertificateReloader)>> {
+    let (certificate_path, key_path) = match (
+        &common.listen_tls_certificate_file,
+        &common.listen_tls_key_file,
+    ) {
+        (Some(certificate_path), Some(key_path)) => (certificate_path, key_path),
+        (None, None) if config.listen_tls_client_ca_file.is_some() => {
+            return Err(anyhow!(
+                "client certificate verification needs TLS to be enabled"
+            ));
+        }
+        (None, None) => return Ok(None),
+        _ => return Err(anyhow!("both TLS certificate and key files must be given")),
+    };
+
+    let provider = Arc::new(ring::default_provider());
+    let reloader = CertificateReloader::new(certificate_path, key_path, Arc::clone(&provider))?;
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .context("error setting TLS protocol versions")?;
+    let builder = match &config.listen_tls_client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("error reading client CA file {}", path.display()))?
+            {
+                let certificate = certificate
+                    .with_context(|| format!("error reading client CA file {}", path.display()))?;
+                roots
+                    .add(certificate)
+                    .context("error adding client CA certificate")?;
+            }
+            // Client certificates are only required by the subscribe proxy endpoint, so that
+            // health checks can still connect without one.
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .context("error building client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(Arc::new(reloader.resolver()));
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Some((TlsAcceptor::from(Arc::new(server_config)), reloader)))
+}
+
+type Handshake = Option<(TlsStream<TcpStream>, SocketAddr)>;
+
+/// Listener of the HTTP API, performing TLS handshakes if TLS is enabled.
+pub(crate) struct HttpListener {
+    tcp: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    handshakes: JoinSet<Handshake>,
+}
+
+impl HttpListener {
+    pub(crate) fn new(tcp: TcpListener, acceptor: Option<TlsAcceptor>) -> Self {
+        Self {
+            tcp,
+            acceptor,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+impl Listener for HttpListener {
+    type Io = Either<TcpStream, TlsStream<TcpStream>>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                accepted = self.tcp.accept() => {
+                    let (stream, remote_addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            error!(kind = "TCP accept", %err);
+                            time::sleep(ACCEPT_ERROR_DELAY).await;
+                            continue;
+                        }
+                    };
+                    let Some(acceptor) = &self.acceptor else {
+                        return (Either::Left(stream), remote_addr);
+                    };
+                    let handshake = acceptor.accept(stream);
+                    self.handshakes.spawn(async move {
+                        match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                            Ok(Ok(stream)) => Some((stream, remote_addr)),
+                            Ok(Err(err)) => {
+                                error!(kind = "TLS handshake", %remote_addr, %err);
+                                None
+                            }
+                            Err(_) => {
+                                error!(kind = "TLS handshake timeout", %remote_addr);
+                                None
+                            }
+                        }
+                    });
+                }
+                Some(joined) = self.handshakes.join_next() => {
+                    if let Ok(Some((stream, remote_addr))) = joined {
+                        return (Either::Right(stream), remote_addr);
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.tcp.local_addr()
+    }
+}
+
+/// Connection information available to request handlers.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionInfo {
+    /// Whether the client presented a verified certificate.
+    pub(crate) client_certificate: bool,
+}
+
+impl Connected<IncomingStream<'_, HttpListener>> for ConnectionInfo {
+    fn connect_info(stream: IncomingStream<'_, HttpListener>) -> Self {
+        let client_certificate = match stream.io() {
+            Either::Left(_) => false,
+            Either::Right(stream) => stream.get_ref().1.peer_certificates().is_some(),
+        };
+        Self { client_certificate }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn common_args(certificate: Option<&str>, key: Option<&str>) -> CommonArgs {
+        CommonArgs {
+            listen_address: "127.0.0.1:8080".parse().unwrap(),
+            listen_tls_certificate_file: certificate.map(PathBuf::from),
+            listen_tls_key_file: key.map(PathBuf::from),
+        }
+    }
+
+    mod acceptor {
+        use super::*;
+
+        #[test]
+        fn disabled() {
+            let config = Config {
+                listen_tls_client_ca_file: None,
+            };
+            assert!(
+                acceptor(&common_args(None, None), &config)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        #[test]
+        fn missing_key() {
+            let config = Config {
+                listen_tls_client_ca_file: None,
+            };
+            let common = common_args(Some("/cert.pem"), None);
+            assert!(acceptor(&common, &config).is_err());
+        }
+
+        #[test]
+        fn client_ca_without_tls() {
+            let config = Config {
+                listen_tls_client_ca_file: Some(PathBuf::from("/ca.pem")),
+            };
+            assert!(acceptor(&common_args(None, None), &config).is_err());
+        }
+
+        #[test]
+        fn missing_files() {
+            let config = Config {
+                listen_tls_client_ca_file: None,
+            };
+            let common = common_args(Some("/nonexistent.pem"), Some("/nonexistent.key"));
+            assert!(acceptor(&common, &config).is_err());
+        }
+    }
+
+    mod handshake {
+        use tokio_rustls::TlsConnector;
+        use tokio_rustls::rustls::ClientConfig;
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        use super::*;
+
+        const FIRST: (&str, &str) = (
+            include_str!("../tests/fixtures/tls-1.pem"),
+            include_str!("../tests/fixtures/tls-1.key"),
+        );
+        const SECOND: (&str, &str) = (
+            include_str!("../tests/fixtures/tls-2.pem"),
+            include_str!("../tests/fixtures/tls-2.key"),
+        );
+
+        struct Files(PathBuf, PathBuf);
+
+        impl Files {
+            fn new(name: &str) -> Self {
+                let path = |extension| {
+                    std::env::temp_dir().join(format!(
+                        "centrifugo-change-stream-tls-{name}-tests-{}.{extension}",
+                        std::process::id()
+                    ))
+                };
+                let files = Self(path("pem"), path("key"));
+                files.write(FIRST);
+                files
+            }
+
+            fn write(&self, (certificate, key): (&str, &str)) {
+                std::fs::write(&self.0, certificate).unwrap();
+                std::fs::write(&self.1, key).unwrap();
+                for path in [&self.0, &self.1] {
+                    let file = std::fs::File::options().write(true).open(path).unwrap();
+                    file.set_modified(SystemTime::now() + Duration::from_secs(1))
+                        .unwrap();
+                }
+            }
+
+            fn acceptor(&self) -> (TlsAcceptor, CertificateReloader) {
+                let common = common_args(self.0.to_str(), self.1.to_str());
+                let config = Config {
+                    listen_tls_client_ca_file: None,
+                };
+                acceptor(&common, &config).unwrap().unwrap()
+            }
+        }
+
+        impl Drop for Files {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+                let _ = std::fs::remove_file(&self.1);
+            }
+        }
+
+        fn der((certificate, _): (&str, &str)) -> CertificateDer<'static> {
+            CertificateDer::from_pem_slice(certificate.as_bytes()).unwrap()
+        }
+
+        /// Connects to the acceptor, returning the certificate presented by the server.
+        async fn server_certificate(acceptor: &TlsAcceptor) -> CertificateDer<'static> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let acceptor = acceptor.clone();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                acceptor.accept(stream).await.unwrap();
+            });
+
+            let mut roots = RootCertStore::empty();
+            roots.add(der(FIRST)).unwrap();
+            roots.add(der(SECOND)).unwrap();
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let stream = TlsConnector::from(Arc::new(config))
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            server.await.unwrap();
+            let certificates = stream.get_ref().1.peer_certificates().unwrap();
+            certificates[0].clone().into_owned()
+        }
+
+        #[tokio::test]
+        async fn success() {
+            let files = Files::new("success");
+            let (acceptor, _) = files.acceptor();
+            assert_eq!(server_certificate(&acceptor).await, der(FIRST));
+        }
+
+        #[tokio::test]
+        async fn reload() {
+            let files = Files::new("reload");
+            let (acceptor, mut reloader) = files.acceptor();
+            files.write(SECOND);
+            assert_eq!(server_certificate(&acceptor).await, der(FIRST));
+            reloader.reload_if_modified();
+            assert_eq!(server_certificate(&acceptor).await, der(SECOND));
+        }
+
+        #[tokio::test]
+        async fn invalid_reload() {
+            let files = Files::new("invalid-reload");
+            let (acceptor, mut reloader) = files.acceptor();
+            files.write((SECOND.0, FIRST.1));
+            reloader.reload_if_modified();
+            assert_eq!(server_certificate(&acceptor).await, der(FIRST));
+        }
+    }
+}